@@ -7,90 +7,283 @@ use model;
 use path;
 use server::*;
 
+// durations in commands are in seconds, and get rounded up to whole ticks,
+// angles are in degrees
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Command {
-    Nav(Vec2),
+    Nav(Vec2, model::MoveMode),
     Wait(f64),
     Shoot(EID),
+    // shoot while closing in on the target, as fast as the weapon allows
+    Charge(EID),
+    Throw(Vec2),
+    Face(f64),
+    Suppress(Vec2, f64),
+    Overwatch(Vec2, f64),
+    Switch(model::Weapon),
+    Reload,
+    Operate(EID),
 }
 
 impl model::UnitState {
-    pub fn update_pos(self: &mut Self, new_time: f64) {
-        let vel = vec2_scale(self.vel, new_time - self.time);
+    // velocities are in distance per tick
+    pub fn update_pos(self: &mut Self, new_time: Time) {
+        let dt = (new_time - self.time) as f64;
+        let vel = vec2_scale(self.vel, dt);
         let pos = vec2_add(self.pos, vel);
         self.pos = pos;
+        self.facing += self.spin * dt;
         self.time = new_time;
     }
 
+    // health and ammunition are worked out by the server, so they don't
+    // count as the server disagreeing with the move that was sent
+    fn same_move(self: Self, other: Self) -> bool {
+        let mut other = other;
+        other.health = self.health;
+        other.loadout = self.loadout;
+        self == other
+    }
+
     fn command_end(self: &mut Self, comm: Command) {
         match comm {
-            Command::Nav(_) => {
+            Command::Nav(_, _) => {
+                self.vel = [0.0, 0.0];
+            },
+            Command::Charge(_) => {
                 self.vel = [0.0, 0.0];
+                self.action = model::Action::Mobile;
+                self.target_id = NULL_ID;
+                self.target_loc = [0.0, 0.0];
             },
-            Command::Shoot(_) => {
+            Command::Shoot(_)
+            | Command::Throw(_)
+            | Command::Suppress(..)
+            | Command::Overwatch(..)
+            | Command::Switch(_)
+            | Command::Reload
+            | Command::Operate(_) => {
                 self.action = model::Action::Mobile;
                 self.target_id = NULL_ID;
                 self.target_loc = [0.0, 0.0];
             },
+            Command::Face(_) => {
+                self.spin = 0.0;
+            },
             Command::Wait(_) => (),
         }
     }
 
-    fn command_start(self: &mut Self, comm: Command) -> f64 {
-        let duration = self.command_duration(comm);
+    fn command_start(
+        self: &mut Self,
+        rules: &model::Rules,
+        map: &path::Map,
+        current: &model::Snapshot,
+        comm: Command,
+    ) -> Ticks {
         match comm {
-            Command::Nav(pos) => {
+            Command::Nav(pos, mode) => {
+                self.mode = mode;
+                let duration = self.command_duration(rules, map, comm);
                 let disp = vecmath::vec2_sub(pos, self.pos);
-                self.vel = vecmath::vec2_scale(disp, 1.0/duration);
+                self.vel = vecmath::vec2_scale(disp, 1.0/duration as f64);
             },
             Command::Shoot(target) => {
+                let mut target_state = current.states[&target];
+                target_state.update_pos(self.time);
                 self.target_id = target;
+                self.target_loc = target_state.pos;
                 self.action = model::Action::Shoot;
             },
+            Command::Charge(target) => {
+                self.command_start(rules, map, current, Command::Shoot(target));
+                // heads for where the target was, and stops short of it once
+                // the kill is done
+                let disp = vecmath::vec2_sub(self.target_loc, self.pos);
+                let dist = vecmath::vec2_len(disp);
+                let gap = dist - 2.0 * rules.unit_radius;
+                let fire_speed = rules.weapon(self.weapon).fire_speed;
+                if gap > 0.0 && fire_speed > 0.0 {
+                    let end = vec2_add(self.pos, vec2_scale(disp, gap / dist));
+                    let terrain = rules.slowest_terrain(map, self.pos, end);
+                    let speed = fire_speed
+                        .min(rules.move_mode(self.mode).speed * terrain);
+                    let duration = rules.kill_time(self) as f64;
+                    let speed = rules.speed(speed).min(gap / duration);
+                    self.vel = vec2_scale(disp, speed / dist);
+                }
+            },
+            Command::Throw(pos) => {
+                // throws that are too long fall short rather than failing
+                let disp = vecmath::vec2_sub(pos, self.pos);
+                let dist = vecmath::vec2_len(disp);
+                let range = rules.grenade.range;
+                self.target_loc = if dist > range {
+                    let disp = vecmath::vec2_scale(disp, range / dist);
+                    vecmath::vec2_add(self.pos, disp)
+                } else {
+                    pos
+                };
+                self.vel = [0.0, 0.0];
+                self.action = model::Action::Throw;
+            },
+            Command::Suppress(pos, _) => {
+                self.target_id = NULL_ID;
+                self.target_loc = pos;
+                self.vel = [0.0, 0.0];
+                self.action = model::Action::Suppress;
+            },
+            Command::Overwatch(pos, _) => {
+                self.target_id = NULL_ID;
+                self.target_loc = pos;
+                self.vel = [0.0, 0.0];
+                self.action = model::Action::Overwatch;
+            },
+            Command::Switch(weapon) => {
+                self.target_id = NULL_ID;
+                self.target_loc = [0.0, 0.0];
+                self.weapon = weapon;
+                self.action = model::Action::Switch;
+            },
+            Command::Reload => {
+                self.target_id = NULL_ID;
+                self.target_loc = [0.0, 0.0];
+                self.action = model::Action::Reload;
+            },
+            Command::Operate(door) => {
+                self.target_id = door;
+                self.target_loc = [0.0, 0.0];
+                self.vel = [0.0, 0.0];
+                self.action = model::Action::Operate;
+            },
+            Command::Face(angle) => {
+                let duration = self.command_duration(rules, map, comm);
+                let angle = angle.to_radians();
+                let turn = model::angle_between(self.facing, angle);
+                self.spin = turn / duration as f64;
+            },
             Command::Wait(_) => (),
         }
-        duration
+        self.command_duration(rules, map, comm)
     }
 
-    fn walk_duration(self: &Self, pos: Vec2) -> f64 {
+    // walking over slow ground takes longer, so the walk is split up wherever
+    // the speed changes
+    fn walk_duration(
+        self: &Self,
+        rules: &model::Rules,
+        map: &path::Map,
+        pos: Vec2,
+        mode: model::MoveMode,
+    ) -> Ticks {
         let disp = vecmath::vec2_sub(pos, self.pos);
-        let max_speed = 1.0;
-        let min_duration = vecmath::vec2_len(disp) / max_speed;
-        let duration = (min_duration*10.0).ceil()*0.1;
-        // prevents NaN, but 0-length commands currently cause problems anyway
-        if duration == 0.0 {
-            0.1
-        } else {
-            duration
+        let max_speed = rules.move_mode(mode).speed;
+        let mut bounds = rules.terrain_changes(map, self.pos, pos);
+        bounds.insert(0, 0.0);
+        bounds.push(1.0);
+        let mut min_duration = 0.0;
+        for i in 1..bounds.len() {
+            let mid = (bounds[i - 1] + bounds[i]) / 2.0;
+            let mid = vec2_add(self.pos, vec2_scale(disp, mid));
+            let speed = max_speed * rules.terrain_speed(map, mid);
+            let len = vecmath::vec2_len(disp) * (bounds[i] - bounds[i - 1]);
+            min_duration += len / speed;
         }
+        // prevents NaN, but 0-length commands currently cause problems anyway
+        rules.min_ticks(min_duration)
     }
 
-    fn command_duration(self: &Self, comm: Command) -> f64 {
+    // for commands that change the unit's state this should be called after
+    // command_start
+    fn command_duration(
+        self: &Self,
+        rules: &model::Rules,
+        map: &path::Map,
+        comm: Command,
+    ) -> Ticks {
         match comm {
-            Command::Nav(pos) => {
-                self.walk_duration(pos)
+            Command::Nav(pos, mode) => {
+                self.walk_duration(rules, map, pos, mode)
+            },
+            Command::Wait(duration)
+            | Command::Suppress(_, duration)
+            | Command::Overwatch(_, duration) => {
+                rules.ticks(duration)
+            },
+            Command::Shoot(_) | Command::Charge(_) => {
+                rules.kill_time(self)
+            },
+            Command::Throw(_) => {
+                rules.throw_time()
             },
-            Command::Wait(duration) => {
-                duration
+            Command::Switch(weapon) => {
+                rules.swap_time(weapon)
             },
-            Command::Shoot(_) => {
-                5.0
+            Command::Reload => {
+                rules.reload_time(self.weapon)
+            },
+            Command::Operate(_) => {
+                rules.door_time()
+            },
+            Command::Face(angle) => {
+                let angle = angle.to_radians();
+                let turn = model::angle_between(self.facing, angle);
+                rules.turn_time(turn)
             },
         }
     }
 
     // infers a command that would start with the given unit state, and might
     // finish at the given time
-    fn infer_command(self: Self, finish: f64) -> Option<(f64, Command)> {
+    fn infer_command(
+        self: Self,
+        rules: &model::Rules,
+        finish: Time,
+    ) -> Option<(Time, Command)> {
         use model::Action::*;
         match self.action {
-            Shoot => Some((self.time + 5.0, Command::Shoot(self.target_id))),
-            Mobile => if self.vel == [0.0, 0.0] {
+            Shoot => {
+                let end = self.time + rules.kill_time(&self);
+                if self.vel == [0.0, 0.0] {
+                    Some((end, Command::Shoot(self.target_id)))
+                } else {
+                    Some((end, Command::Charge(self.target_id)))
+                }
+            },
+            Throw => {
+                let end = self.time + rules.throw_time();
+                Some((end, Command::Throw(self.target_loc)))
+            },
+            Suppress => {
+                let duration = rules.secs(finish - self.time);
+                Some((finish, Command::Suppress(self.target_loc, duration)))
+            },
+            Overwatch => {
+                let duration = rules.secs(finish - self.time);
+                Some((finish, Command::Overwatch(self.target_loc, duration)))
+            },
+            Switch => {
+                let end = self.time + rules.swap_time(self.weapon);
+                Some((end, Command::Switch(self.weapon)))
+            },
+            Reload => {
+                let end = self.time + rules.reload_time(self.weapon);
+                Some((end, Command::Reload))
+            },
+            Operate => {
+                let end = self.time + rules.door_time();
+                Some((end, Command::Operate(self.target_id)))
+            },
+            Mobile => if self.vel == [0.0, 0.0] && self.spin == 0.0 {
                 None
+            } else if self.vel == [0.0, 0.0] {
+                let mut dummy = self;
+                dummy.update_pos(finish);
+                Some((finish, Command::Face(dummy.facing.to_degrees())))
             } else {
                 let mut dummy = self;
                 dummy.update_pos(finish);
-                Some((finish, Command::Nav(dummy.pos)))
+                Some((finish, Command::Nav(dummy.pos, self.mode)))
             },
             _ => None,
         }
@@ -102,18 +295,23 @@ pub type Plan = HashMap<EID, Vec<Command>>;
 pub struct Client {
     pub map: path::Map,
     //pub mesh: path::NavMesh,
+    pub rules: model::Rules,
 
     pub init: model::Snapshot,
     pub confirmed: model::Timeline,
     pub current: model::Snapshot,
-    pub current_commands: HashMap<EID, Option<(f64, Command)>>,
-    pub cancel: HashMap<EID, Option<f64>>,
+    pub current_commands: HashMap<EID, Option<(Time, Command)>>,
+    pub cancel: HashMap<EID, Option<Time>>,
     pub plans: Plan,
 }
 
 
 impl Client {
-    pub fn new(init: model::Snapshot, map: path::Map) -> Self {
+    pub fn new(
+        init: model::Snapshot,
+        map: path::Map,
+        rules: model::Rules,
+    ) -> Self {
         //let mesh = path::NavMesh::generate(&map, 1.0);
         let confirmed = model::Timeline::new();
         let current = init.clone();
@@ -123,6 +321,7 @@ impl Client {
         Client {
             map,
             //mesh,
+            rules,
 
             init,
             confirmed,
@@ -133,12 +332,36 @@ impl Client {
         }
     }
 
+    // plans read from files can name targets that aren't in the game
+    pub fn check_commands(
+        self: &Self,
+        commands: &[Command],
+    ) -> Result<(), String> {
+        for &comm in commands {
+            match comm {
+                Command::Shoot(target)
+                | Command::Charge(target)
+                | Command::Operate(target) => {
+                    if !self.current.states.contains_key(&target) {
+                        return Err(format!("Unknown target {}", target));
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
     fn gen_planpaths(self: &Self) -> Plan {
         self.plans.clone()
     }
 
     pub fn gen_planned(self: &Self) -> (Plan, model::Timeline) {
-        let mut sims = Server::new(self.current.clone(), self.map.clone());
+        let mut sims = Server::new(
+            self.current.clone(),
+            self.map.clone(),
+            self.rules.clone(),
+        );
 
         let paths = self.gen_planpaths();
         // this is kinda dirty
@@ -147,6 +370,7 @@ impl Client {
         let mut simc = Client {
             map: self.map.clone(),  // hmm...
             //mesh: self.mesh.clone(),
+            rules: self.rules.clone(),
 
             init: model::Snapshot::new(),
             confirmed: model::Timeline::new(),
@@ -179,38 +403,7 @@ impl Client {
         let mut moves = HashMap::new();
         for (&id, &comm) in &self.current_commands {
             let old_state = self.current.states[&id];
-            let mut state = old_state;
-            let plan = self.plans.get(&id).unwrap();
-            let new_comm = plan.get(0).cloned();
-            if comm.is_some() || new_comm.is_some() {
-                let mut time = self.current.time + 0.1;
-                if let Some((mut ctime, _)) = comm {
-                    let cancel = self.cancel[&id];
-                    if let Some(cctime) = cancel {
-                        if cctime < ctime {
-                            ctime = cctime;
-                        }
-                    }
-                    if ctime > time {
-                        time = ctime;
-                    }
-                }
-                state.update_pos(time);
-            }
-            if let Some((_, comm)) = comm {
-                state.command_end(comm);
-            }
-            if let Some(new_comm) = new_comm {
-                let mut comm_state = state;
-                comm_state.command_start(new_comm);
-                if !Server::collision_imminent(
-                    &self.map,
-                    &self.current,
-                    comm_state,
-                ) {
-                    state = comm_state;
-                }
-            }
+            let (state, new_comm) = self.next_move(id);
             if state != old_state {
                 moves.insert(id, state);
                 if state.time == old_state.time {
@@ -225,41 +418,188 @@ impl Client {
         moves
     }
 
+    // units have to turn to face their target before they can shoot it, and
+    // have to change speed wherever the ground does
+    fn expand(self: &Self, state: model::UnitState, comm: Command) -> Command {
+        if let Command::Nav(pos, mode) = comm {
+            let changes = self.rules.terrain_changes(&self.map, state.pos, pos);
+            if let Some(&t) = changes.first() {
+                let disp = vec2_scale(vec2_sub(pos, state.pos), t);
+                return Command::Nav(vec2_add(state.pos, disp), mode);
+            }
+            return comm;
+        }
+        if let Command::Overwatch(pos, _) = comm {
+            // overwatch is centred on the position being watched
+            let angle = model::direction(vec2_sub(pos, state.pos));
+            if model::angle_between(state.facing, angle).abs() > 1e-6 {
+                return Command::Face(angle.to_degrees());
+            }
+            return comm;
+        }
+        let target = match comm {
+            Command::Shoot(target) | Command::Charge(target) => {
+                let mut target = self.current.states[&target];
+                target.update_pos(state.time);
+                target.pos
+            },
+            Command::Suppress(pos, _) => pos,
+            _ => return comm,
+        };
+        if self.rules.in_view(&state, target) {
+            comm
+        } else {
+            let disp = vec2_sub(target, state.pos);
+            Command::Face(model::direction(disp).to_degrees())
+        }
+    }
+
+    // the state that a unit will move to next, and the command that it will
+    // have started, which might not be the one at the front of its plan
+    fn next_move(self: &Self, id: EID) -> (model::UnitState, Option<Command>) {
+        let comm = self.current_commands[&id];
+        let mut state = self.current.states[&id];
+        let plan = self.plans.get(&id).unwrap();
+        let mut new_comm = plan.get(0).cloned();
+        if comm.is_some() || new_comm.is_some() {
+            let mut time = self.current.time + 1;
+            if let Some((mut ctime, comm)) = comm {
+                let cancel = self.cancel[&id];
+                // switching weapons can't be cut short
+                let switching = match comm {
+                    Command::Switch(_) => true,
+                    _ => false,
+                };
+                if let Some(cctime) = cancel.filter(|_| !switching) {
+                    if cctime < ctime {
+                        ctime = cctime;
+                    }
+                }
+                if ctime > time {
+                    time = ctime;
+                }
+            }
+            state.loadout = self.rules.ammo_at(&state, time);
+            state.update_pos(time);
+        }
+        if let Some((_, comm)) = comm {
+            state.command_end(comm);
+        }
+        if let Some(comm) = new_comm {
+            let comm = self.expand(state, comm);
+            new_comm = Some(comm);
+            let mut comm_state = state;
+            comm_state.command_start(
+                &self.rules,
+                &self.map,
+                &self.current,
+                comm,
+            );
+            if Server::is_valid_weapon(&self.rules, state, comm_state)
+                && !Server::collision_imminent(
+                    &self.map,
+                    &self.rules,
+                    &self.current,
+                    comm_state,
+                )
+            {
+                state = comm_state;
+            }
+        }
+        (state, new_comm)
+    }
+
     pub fn accept_outcome(
         self: &mut Self,
         expected: &HashMap<EID, model::UnitState>,
         outcome: &model::Snapshot,
     ) {
+        // work out which commands were started before anything changes
+        let started: HashMap<EID, Option<Command>> = self.current_commands
+            .keys()
+            .map(|&id| (id, self.next_move(id).1))
+            .collect();
         self.current.time = outcome.time;
-        self.confirmed.snapshots.insert(Time(outcome.time), outcome.clone());
+        self.confirmed.snapshots.insert(outcome.time, outcome.clone());
         for (&id, &unit) in &outcome.states {
+            // units we didn't move were expected to stay as they were
+            let moved = expected.get(&id).cloned();
+            let old = self.current.states.get(&id).cloned();
             self.current.states.insert(id, unit);
-            let mut expected = expected
-                .get(&id)
-                .cloned()
-                .unwrap_or(self.current.states[&id]);
+            if unit.action == model::Action::Rubble {
+                path::destroy(&mut self.map, id);
+                //self.mesh = path::NavMesh::generate(&self.map, 1.0);
+            }
+            // grenades, doors and walls don't take commands
+            if !unit.action.is_unit() {
+                continue;
+            }
+            let moved = moved.filter(|moved| moved.time == unit.time);
+            if moved.is_none() && old.is_some() {
+                // units being shot get new states just to record damage,
+                // which shouldn't interrupt what they were doing
+                let mut old = old.unwrap();
+                old.update_pos(unit.time);
+                if unit.same_move(old) {
+                    continue;
+                }
+                // units on overwatch start shooting on their own, units that
+                // run out of rounds reload on their own and then go back to
+                // their target, and units stop shooting once their target is
+                // dead, and then they carry on with their plan once they're
+                // done
+                use model::Action::*;
+                let target_gone = self.current.states
+                    .get(&old.target_id)
+                    .map_or(false, |target| {
+                        target.action == Dead || target.action == Rubble
+                    });
+                let automatic = match (old.action, unit.action) {
+                    (Overwatch, Shoot)
+                    | (Shoot, Reload)
+                    | (Suppress, Reload) => true,
+                    (Shoot, Mobile) => target_gone,
+                    _ => false,
+                };
+                if automatic {
+                    // a shot cut short by a reload picks up again afterwards
+                    let reloading = unit.action == Reload;
+                    if old.action == Shoot && reloading && !target_gone {
+                        let plan = self.plans.get_mut(&id).unwrap();
+                        plan.insert(0, Command::Shoot(old.target_id));
+                    }
+                    self.cancel.insert(id, None);
+                    let rules = &self.rules;
+                    let comm = unit.infer_command(rules, self.current.time);
+                    self.current_commands.insert(id, comm);
+                    continue;
+                }
+            }
             self.cancel.insert(id, None);
-            if unit == expected {
+            let expected = moved.or(old);
+            if expected.map_or(false, |expected| unit.same_move(expected)) {
                 let plan = &mut self.plans.get_mut(&id).unwrap();
-                let comm = if plan.len() > 0 {
-                    Some(plan.remove(0))
-                } else {
-                    None
-                };
+                let comm = started.get(&id).cloned().unwrap_or(None);
+                // turning to face a target leaves the shot in the plan
+                if comm.is_some() && plan.get(0) == comm.as_ref() {
+                    plan.remove(0);
+                }
+                let rules = &self.rules;
+                let map = &self.map;
                 let comm = comm.map(|c|
-                    (unit.time + unit.command_duration(c), c)
+                    (unit.time + unit.command_duration(rules, map, c), c)
                 );
                 self.current_commands.insert(id, comm);
             } else {
                 self.plans.insert(id, Vec::new());
-                let comm = unit.infer_command(self.current.time);
+                let comm = unit.infer_command(&self.rules, self.current.time);
                 self.current_commands.insert(id, comm);
             }
         }
     }
 
     pub fn next_pos(self: &Self, id: EID) -> Option<Vec2> {
-        if let Some((t, Command::Nav(_))) = self.current_commands[&id] {
+        if let Some((t, Command::Nav(_, _))) = self.current_commands[&id] {
             let time = self.cancel[&id].unwrap_or(t);
             let mut unit = self.current.states[&id];
             unit.update_pos(time);
@@ -9,12 +9,22 @@ use std::net;
 use prelude::*;
 
 use model;
+use path;
+use save;
 use client::*;
+use server::Server;
+use script;
 
 pub struct ClientApp {
     client: Client,
     server: net::TcpStream,
     waiting: ServerState,
+    team: TID,
+    bounds: Option<path::Bounds>,
+    info: save::ScenarioInfo,
+    // size of the window when it was last drawn, for working out where the
+    // mouse is
+    view_size: Vec2,
 
     display: model::Snapshot,
     updates: HashMap<EID, Update>,
@@ -24,6 +34,7 @@ pub struct ClientApp {
     selected: EID,
     mouse: Vec2,
     playing: bool,
+    plan_path: String,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -56,48 +67,99 @@ struct Controls {
     continuec: window::Button,
     feint: window::Button,
     nav: window::Button,
+    sprint: window::Button,
+    crouch: window::Button,
     shoot: window::Button,
+    charge: window::Button,
     wait: window::Button,
+    throw: window::Button,
+    suppress: window::Button,
+    overwatch: window::Button,
+    switch: window::Button,
+    reload: window::Button,
+    operate: window::Button,
     playpause: window::Button,
     advance: window::Button,
     unadvance: window::Button,
     restart: window::Button,
     submit: window::Button,
+    save_plan: window::Button,
+    load_plan: window::Button,
 }
 
 static CONTROLS: Controls = Controls {
     select:      window::Button::Mouse(window::mouse::MouseButton::Left),
     nav:         window::Button::Mouse(window::mouse::MouseButton::Right),
+    sprint:      window::Button::Keyboard(window::keyboard::Key::E),
+    crouch:      window::Button::Keyboard(window::keyboard::Key::X),
     remove_comm: window::Button::Keyboard(window::keyboard::Key::Backspace),
     continuec:   window::Button::Keyboard(window::keyboard::Key::C),
     // TODO shift+c, in line with shift+q and shift+RMB
     feint:       window::Button::Keyboard(window::keyboard::Key::F),
     shoot:       window::Button::Keyboard(window::keyboard::Key::Q),
+    charge:      window::Button::Keyboard(window::keyboard::Key::V),
     wait:        window::Button::Keyboard(window::keyboard::Key::W),
+    throw:       window::Button::Keyboard(window::keyboard::Key::G),
+    suppress:    window::Button::Keyboard(window::keyboard::Key::A),
+    overwatch:   window::Button::Keyboard(window::keyboard::Key::O),
+    switch:      window::Button::Keyboard(window::keyboard::Key::Z),
+    reload:      window::Button::Keyboard(window::keyboard::Key::T),
+    operate:     window::Button::Keyboard(window::keyboard::Key::D),
     playpause:   window::Button::Keyboard(window::keyboard::Key::Space),
     advance:     window::Button::Keyboard(window::keyboard::Key::Period),
     unadvance:   window::Button::Keyboard(window::keyboard::Key::Comma),
     restart:     window::Button::Keyboard(window::keyboard::Key::R),
     submit:      window::Button::Keyboard(window::keyboard::Key::Return),
+    save_plan:   window::Button::Keyboard(window::keyboard::Key::S),
+    load_plan:   window::Button::Keyboard(window::keyboard::Key::L),
 };
 
 impl ClientApp {
-    pub fn new<I: net::ToSocketAddrs>(ip: I, name: String) -> Self {
+    pub fn new<I: net::ToSocketAddrs>(
+        ip: I,
+        name: String,
+        team: Option<TID>,
+        plan_path: String,
+    ) -> Self {
         //init: model::Snapshot, map: path::Map) -> Self {
         let server = net::TcpStream::connect(ip).expect("Failed to connect");
         ::bincode::serialize_into(&server, &name)
             .expect("Failed to send name to server");
+        ::bincode::serialize_into(&server, &team)
+            .expect("Failed to send team preference to server");
+        let team: TID = ::bincode::deserialize_from(&server)
+            .expect("Failed to download team");
         let map = ::bincode::deserialize_from(&server)
             .expect("Failed to download/parse map");
+        let bounds = ::bincode::deserialize_from(&server)
+            .expect("Failed to download/parse arena bounds");
+        let info: save::ScenarioInfo = ::bincode::deserialize_from(&server)
+            .expect("Failed to download/parse scenario info");
+        if info.title != "" {
+            println!("{}", info.title);
+        }
+        if info.author != "" {
+            println!("by {}", info.author);
+        }
+        if info.description != "" {
+            println!("{}", info.description);
+        }
+        println!("Joined as {}", info.team(team).name);
+        let rules = ::bincode::deserialize_from(&server)
+            .expect("Failed to download/parse rules");
         let init = ::bincode::deserialize_from(&server)
             .expect("Failed to download/parse unit states");
-        let client = Client::new(init, map);
+        let client = Client::new(init, map, rules);
 
         let display = client.init.clone();
         let mut result = ClientApp {
             client,
             server,
             waiting: ServerState::Joining,
+            team,
+            bounds,
+            info,
+            view_size: [600.0, 600.0],
 
             display,
             updates: HashMap::new(),
@@ -107,6 +169,7 @@ impl ClientApp {
             mouse: [0.0, 0.0],
             playing: false,
             selected: NULL_ID,
+            plan_path,
         };
         result.regen();
         result
@@ -116,6 +179,10 @@ impl ClientApp {
         let mut select = NULL_ID;
         let mut select_dist = 0.0;
         for (&id, val) in &self.display.states {
+            // units that can't be seen shouldn't give themselves away
+            if !val.action.is_unit() || !self.is_visible(*val) {
+                continue;
+            }
             let disp = vecmath::vec2_sub(val.pos, self.mouse);
             let dist = vecmath::vec2_len(disp);
             if select == NULL_ID || dist < select_dist {
@@ -126,11 +193,67 @@ impl ClientApp {
         return select;
     }
 
+    // walls that can be destroyed can be shot at as well as units
+    fn target_nearest_mouse(self: &Self) -> EID {
+        let mut select = self.unit_nearest_mouse();
+        for (&id, val) in &self.display.states {
+            if val.action != model::Action::Wall || !self.is_visible(*val) {
+                continue;
+            }
+            let wall = &self.client.map;
+            if path::near_wall(wall, id, self.mouse, 0.5) {
+                select = id;
+            }
+        }
+        select
+    }
+
+    fn door_nearest_mouse(self: &Self) -> EID {
+        let mut select = NULL_ID;
+        let mut select_dist = 0.0;
+        for (&id, val) in &self.display.states {
+            if !val.action.is_door() {
+                continue;
+            }
+            let mid = vecmath::vec2_scale(
+                vecmath::vec2_add(val.pos, val.target_loc),
+                0.5,
+            );
+            let dist = vecmath::vec2_len(vecmath::vec2_sub(mid, self.mouse));
+            if select == NULL_ID || dist < select_dist {
+                select = id;
+                select_dist = dist;
+            }
+        }
+        return select;
+    }
+
+    // enemy units are only drawn while one of our units can see them
+    fn is_visible(self: &Self, unit: model::UnitState) -> bool {
+        if unit.team == self.team || !unit.action.is_unit() {
+            return true;
+        }
+        self.display.states.values().any(|&viewer| {
+            viewer.team == self.team
+                && viewer.action.is_unit()
+                && viewer.action != model::Action::Dead
+                && Server::can_see(
+                    &self.client.map,
+                    &self.client.rules,
+                    &self.display,
+                    &viewer,
+                    &unit,
+                )
+        })
+    }
+
     fn edit_plan(self: &mut Self, op: u16) {
         {
             let id = self.selected;
-            let mouse_id = if op == 3 {
-                self.unit_nearest_mouse()
+            let mouse_id = if op == 3 || op == 13 {
+                self.target_nearest_mouse()
+            } else if op == 12 {
+                self.door_nearest_mouse()
             } else {
                 NULL_ID
             };
@@ -152,26 +275,73 @@ impl ClientApp {
                     }
                 },
                 1 => {self.client.cancel.insert(id, None);},
-                2 => plan.push(Command::Nav(mouse)),
+                2 => plan.push(Command::Nav(mouse, model::MoveMode::Walk)),
                 3 => plan.push(Command::Shoot(mouse_id)),
                 4 => plan.push(Command::Wait(1.0)),
+                5 => plan.push(Command::Throw(mouse)),
+                6 => plan.push(Command::Nav(mouse, model::MoveMode::Sprint)),
+                7 => plan.push(Command::Nav(mouse, model::MoveMode::Crouch)),
+                8 => plan.push(Command::Suppress(mouse, 1.0)),
+                9 => plan.push(Command::Overwatch(mouse, 1.0)),
+                10 => {
+                    let unit = self.client.current.states[&id];
+                    let weapon = next_weapon(unit, plan);
+                    plan.push(Command::Switch(weapon));
+                },
+                11 => plan.push(Command::Reload),
+                12 => if mouse_id != NULL_ID {
+                    plan.push(Command::Operate(mouse_id));
+                },
+                13 => plan.push(Command::Charge(mouse_id)),
                 _ => panic!("edit.client called with {}", op),
             }
         }
         self.regen();
     }
 
+    fn save_plan(self: &Self) {
+        match script::write_plan(&self.plan_path, &self.client.plans) {
+            Ok(()) => println!("Saved plan to {}", self.plan_path),
+            Err(msg) => println!("{}", msg),
+        }
+    }
+
+    // only replaces the plans of units that the loaded file mentions
+    fn load_plan(self: &mut Self) {
+        let plan = match script::read_plan(&self.plan_path) {
+            Ok(plan) => plan,
+            Err(msg) => {
+                println!("{}", msg);
+                return;
+            },
+        };
+        for (id, commands) in plan {
+            let team = self.client.init.states.get(&id).map(|unit| unit.team);
+            if !self.client.plans.contains_key(&id) {
+                println!("Ignoring plan for unknown unit {}", id);
+            } else if team != Some(self.team) {
+                println!("Ignoring plan for unit {}, which isn't ours", id);
+            } else if let Err(msg) = self.client.check_commands(&commands) {
+                println!("Ignoring plan for unit {}: {}", id, msg);
+            } else {
+                self.client.plans.insert(id, commands);
+            }
+        }
+        println!("Loaded plan from {}", self.plan_path);
+        self.regen();
+    }
+
     fn regen(self: &mut Self) {
         let time = self.display.time;
         self.regen_with_time(time);
     }
 
-    fn regen_with_time(self: &mut Self, time: f64) {
+    fn regen_with_time(self: &mut Self, time: Time) {
         let (plan, timeline) = self.client.gen_planned();
         self.planpaths = plan;
         self.planned = timeline;
         self.display = self.get_display(time);
-        let prevtime = time - 0.1;
+        let prevtime = time - 1;
         if prevtime >= self.client.init.time {
             let prevdisplay = self.get_display(prevtime);
             self.regen_updates(&prevdisplay);
@@ -187,12 +357,16 @@ impl ClientApp {
             .states
             .iter()
             .map(|(&id, &unit)|
-                 (id, Update::compare(old_states.states[&id], unit))
+                 {
+                     // grenades might not have existed yet
+                     let old = old_states.states.get(&id).cloned();
+                     (id, Update::compare(old.unwrap_or(unit), unit))
+                 }
              )
             .collect();
     }
 
-    fn get_display(self: &Self, time: f64) -> model::Snapshot {
+    fn get_display(self: &Self, time: Time) -> model::Snapshot {
         let mut display;
         if time < self.client.current.time {
             display = self.client.init.clone();
@@ -244,6 +418,19 @@ impl ClientApp {
         }
         self.waiting = ServerState::Display;
     }
+
+    // the scale and the world position of the top left corner of the window,
+    // so that the whole arena fits in the window when it has bounds
+    fn view(self: &Self) -> (f64, Vec2) {
+        match self.bounds {
+            Some([lo, hi]) => {
+                let scale_x = self.view_size[0] / (hi[0] - lo[0]);
+                let scale_y = self.view_size[1] / (hi[1] - lo[1]);
+                (scale_x.min(scale_y), lo)
+            },
+            None => (SCALE, [0.0, 0.0]),
+        }
+    }
 }
 
 static SCALE: f64 = 10.0;
@@ -280,6 +467,24 @@ fn dotted_line(
     }
 }
 
+// cycles through the loadout, starting after whatever weapon the unit will
+// be holding once the rest of its plan is done
+fn next_weapon(unit: model::UnitState, plan: &Vec<Command>) -> model::Weapon {
+    let mut weapon = unit.weapon;
+    for comm in plan {
+        if let &Command::Switch(other) = comm {
+            weapon = other;
+        }
+    }
+    let carried: Vec<model::Weapon> = unit.loadout
+        .iter()
+        .filter_map(|&slot| slot)
+        .map(|slot| slot.weapon)
+        .collect();
+    let pos = carried.iter().position(|&other| other == weapon).unwrap_or(0);
+    carried[(pos + 1) % carried.len()]
+}
+
 fn sys_time() -> f64 {
     use std::time::SystemTime;
     let now = SystemTime::now();
@@ -305,10 +510,17 @@ impl piston_app::App for ClientApp {
             sys_time() % (2.0*flash_speed) < flash_speed;
 
         let unit_color = [1.0, 1.0, 1.0, 1.0];
-        let scale = SCALE;
-        let trans = centre.transform.scale(scale, scale);
+        self.view_size = centre.get_view_size();
+        let (scale, corner) = self.view();
+        let trans = centre.transform
+            .scale(scale, scale)
+            .trans(-corner[0], -corner[1]);
 
         let unit_shape = [-0.5, -0.5, 1.0, 1.0];
+        let crouch_shape = [-0.35, -0.35, 0.7, 0.7];
+        let grenade_shape = [-0.2, -0.2, 0.4, 0.4];
+        let health_color = [1.0, 0.0, 0.0, 1.0];
+        let ammo_color = [1.0, 1.0, 0.0, 1.0];
 
         let vel_pos = 0.7;
         let vel_size = 0.3;
@@ -317,12 +529,95 @@ impl piston_app::App for ClientApp {
             [vel_pos + vel_size, 0.0],
             [vel_pos, -vel_size]
         ];
+        let cone_len = 3.0;
+        let half_view = self.client.rules.field_of_view.to_radians() / 2.0;
         for (&id, &unit) in &self.display.states {
+            if !self.is_visible(unit) {
+                continue;
+            }
             let unit_trans = trans.trans(unit.pos[0], unit.pos[1]);
 
+            match unit.action {
+                model::Action::Grenade(_) => {
+                    window::ellipse(
+                        unit_color,
+                        grenade_shape,
+                        unit_trans,
+                        graphics,
+                    );
+                    continue;
+                },
+                // walls are drawn along with the rest of the map
+                model::Action::Detonated
+                | model::Action::Wall
+                | model::Action::Rubble => continue,
+                model::Action::Open | model::Action::Closed => {
+                    let end = unit.target_loc;
+                    let line = [unit.pos[0], unit.pos[1], end[0], end[1]];
+                    if unit.action == model::Action::Closed {
+                        let r = self.client.rules.door_thickness / 2.0;
+                        window::line(unit_color, r, line, trans, graphics);
+                    } else {
+                        let r = 1.0/scale;
+                        dotted_line(unit_color, r, line, trans, graphics);
+                    }
+                    continue;
+                },
+                _ => (),
+            }
+            let [r, g, b] = self.info.team(unit.team).colour;
+            let team_color = [r, g, b, 1.0];
+
+            if unit.action != model::Action::Dead {
+                for &side in &[-half_view, half_view] {
+                    let angle = unit.facing + side;
+                    let end = [angle.cos() * cone_len, angle.sin() * cone_len];
+                    let line = [0.0, 0.0, end[0], end[1]];
+                    let r = 0.5/scale;
+                    window::line(team_color, r, line, unit_trans, graphics);
+                }
+            }
+
+            if unit.health < model::FULL_HEALTH
+                && unit.action != model::Action::Dead
+            {
+                let width = unit.health / model::FULL_HEALTH;
+                let bar = [-0.5, 0.7, width, 0.15];
+                window::rectangle(health_color, bar, unit_trans, graphics);
+            }
+
+            // rounds in the magazine above spare magazines in the reserve
+            let stats = self.client.rules.weapon(unit.weapon);
+            let slot = unit.slot(unit.weapon);
+            if id == self.selected && stats.mag_size > 0 && slot.is_some() {
+                let slot = slot.unwrap();
+                let size = stats.mag_size as f64;
+                let width = slot.mag as f64 / size;
+                let bar = [-0.5, -0.85, width, 0.15];
+                window::rectangle(ammo_color, bar, unit_trans, graphics);
+                let mags = (slot.reserve as f64 / size).ceil() as usize;
+                for i in 0..mags {
+                    let pip = [-0.5 + 0.25 * i as f64, -1.1, 0.15, 0.15];
+                    window::rectangle(ammo_color, pip, unit_trans, graphics);
+                }
+            }
+
+            if unit.action == model::Action::Throw
+                || unit.action == model::Action::Suppress
+            {
+                let pos = unit.target_loc;
+                let line = [unit.pos[0], unit.pos[1], pos[0], pos[1]];
+                dotted_line(unit_color, 1.0/scale, line, trans, graphics);
+            }
+
             let updates = self.updates.get(&id).cloned().unwrap_or_default();
             if flash_new || !(unit.vel == [0.0;2] && updates.vel) {
-                window::ellipse(unit_color, unit_shape, unit_trans, graphics);
+                let shape = if unit.mode == model::MoveMode::Crouch {
+                    crouch_shape
+                } else {
+                    unit_shape
+                };
+                window::ellipse(team_color, shape, unit_trans, graphics);
             }
 
             if unit.target_id != NULL_ID && (flash_new || !updates.target) {
@@ -360,7 +655,7 @@ impl piston_app::App for ClientApp {
                 pos_list.push(pos);
             }
             for command in plan {
-                if let &Command::Nav(pos) = command {
+                if let &Command::Nav(pos, _) = command {
                     pos_list.push(pos);
                 }
             }
@@ -387,7 +682,7 @@ impl piston_app::App for ClientApp {
                 pos_list.push(pos);
             }
             for command in plan {
-                if let &Command::Nav(pos) = command {
+                if let &Command::Nav(pos, _) = command {
                     pos_list.push(pos);
                 }
             }
@@ -397,19 +692,33 @@ impl piston_app::App for ClientApp {
             }
         }
 
-        let mut tri_list = Vec::with_capacity(client.map.len() * 3);
-        for &trig in &self.client.map {
-            // rust unroll plz <3
-            for p in 0..3 {
-                let x = trig[p][0];
-                let y = trig[p][1];
-                // surely im not meant to be doing this
-                let tx = window::triangulation::tx(trans, x, y);
-                let ty = window::triangulation::ty(trans, x, y);
-                tri_list.push([tx, ty]);
+        let materials = [
+            (path::Material::Wall, path_color),
+            (path::Material::LowCover, [0.6, 0.6, 0.6, 1.0]),
+            (path::Material::Glass, [0.5, 0.8, 1.0, 1.0]),
+            (path::Material::Smoke, [0.3, 0.3, 0.3, 1.0]),
+            (path::Material::Mud, [0.4, 0.3, 0.1, 1.0]),
+            (path::Material::Water, [0.1, 0.3, 0.7, 1.0]),
+            (path::Material::Stairs, [0.5, 0.4, 0.3, 1.0]),
+        ];
+        for &(material, color) in &materials {
+            let mut tri_list = Vec::with_capacity(client.map.len() * 3);
+            for &(trig, other, _) in &self.client.map {
+                if other != material {
+                    continue;
+                }
+                // rust unroll plz <3
+                for p in 0..3 {
+                    let x = trig[p][0];
+                    let y = trig[p][1];
+                    // surely im not meant to be doing this
+                    let tx = window::triangulation::tx(trans, x, y);
+                    let ty = window::triangulation::ty(trans, x, y);
+                    tri_list.push([tx, ty]);
+                }
             }
+            graphics.tri_list(&Default::default(), &color, |f| f(&*tri_list));
         }
-        graphics.tri_list(&Default::default(), &path_color, |f| f(&*tri_list));
 
         use self::ServerState::*;
         match self.waiting {
@@ -443,8 +752,7 @@ impl piston_app::App for ClientApp {
         if !self.playing {
             return
         }
-        let dtime = 0.1;
-        let new_time = self.display.time + dtime;
+        let new_time = self.display.time + 1;
         let old_states = self.display.clone();
         {
             let tl;
@@ -468,6 +776,9 @@ impl piston_app::App for ClientApp {
         if args.state == window::ButtonState::Press {
             if args.button == CONTROLS.select {
                 self.selected = self.unit_nearest_mouse();
+                if self.selected != NULL_ID {
+                    println!("Selected {}", self.info.unit_name(self.selected));
+                }
             } else if args.button == CONTROLS.remove_comm {
                 self.edit_plan(0);
             } else if args.button == CONTROLS.continuec {
@@ -480,16 +791,34 @@ impl piston_app::App for ClientApp {
                 self.edit_plan(2);
             } else if args.button == CONTROLS.shoot {
                 self.edit_plan(3);
+            } else if args.button == CONTROLS.charge {
+                self.edit_plan(13);
             } else if args.button == CONTROLS.wait {
                 self.edit_plan(4);
+            } else if args.button == CONTROLS.throw {
+                self.edit_plan(5);
+            } else if args.button == CONTROLS.suppress {
+                self.edit_plan(8);
+            } else if args.button == CONTROLS.overwatch {
+                self.edit_plan(9);
+            } else if args.button == CONTROLS.switch {
+                self.edit_plan(10);
+            } else if args.button == CONTROLS.reload {
+                self.edit_plan(11);
+            } else if args.button == CONTROLS.operate {
+                self.edit_plan(12);
+            } else if args.button == CONTROLS.sprint {
+                self.edit_plan(6);
+            } else if args.button == CONTROLS.crouch {
+                self.edit_plan(7);
             } else if args.button == CONTROLS.playpause {
                 self.playing = !self.playing;
             } else if args.button == CONTROLS.advance {
-                let time = self.display.time + 0.1;
+                let time = self.display.time + 1;
                 self.regen_with_time(time);
             } else if args.button == CONTROLS.unadvance {
                 if self.display.time > self.client.init.time {
-                    let time = self.display.time - 0.1;
+                    let time = self.display.time - 1;
                     self.regen_with_time(time);
                 }
             } else if args.button == CONTROLS.restart {
@@ -502,6 +831,10 @@ impl piston_app::App for ClientApp {
                     new_dt = self.client.init.time;
                 }
                 self.regen_with_time(new_dt);
+            } else if args.button == CONTROLS.save_plan {
+                self.save_plan();
+            } else if args.button == CONTROLS.load_plan {
+                self.load_plan();
             }
             use self::ServerState::*;
             if args.button == CONTROLS.submit {
@@ -521,7 +854,8 @@ impl piston_app::App for ClientApp {
         self: &mut Self,
         mouse: [f64; 2],
     ) {
-        self.mouse = vecmath::vec2_scale(mouse, 1.0/SCALE);
+        let (scale, corner) = self.view();
+        self.mouse = vec2_add(vecmath::vec2_scale(mouse, 1.0/scale), corner);
     }
 
     fn window_name() -> &'static str {
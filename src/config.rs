@@ -0,0 +1,207 @@
+use std::fs;
+use std::io;
+
+use prelude::*;
+
+pub const DEFAULT_PATH: &str = "tactics.ron";
+
+pub const USAGE: &str = "\
+usage: tactics (server | -s) [SCENARIO] [options]
+       tactics client [options]
+       tactics regress [--bless] CASE...
+       tactics mapgen (demo | conflict | random SEED TEAMS) [OUTPUT]
+
+options:
+    --config PATH     RON file to read defaults from (default tactics.ron)
+    --address ADDR    address to bind to (server) or connect to (client)
+    --port PORT       port to bind to or connect to
+    --scenario PATH   scenario to host, the same as SCENARIO (server only)
+    --name NAME       player name (client only)
+    --team TEAM       preferred team id from the scenario (client only)
+    --plan PATH       file to save and load plans with (client only)
+";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Mode {
+    Server,
+    Client,
+}
+
+// every field can be given in the config file, and then overridden on the
+// command line
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub mode: Mode,
+    pub address: String,
+    pub port: u16,
+    pub scenario: String,
+    pub name: String,
+    pub team: Option<TID>,
+    pub plan: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mode: Mode::Client,
+            address: "127.0.0.1".to_string(),
+            port: 5050,
+            scenario: "map".to_string(),
+            name: "player".to_string(),
+            team: None,
+            plan: "plan.txt".to_string(),
+        }
+    }
+}
+
+impl Config {
+    pub fn read_file(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+        ::ron::de::from_str(&contents)
+            .map_err(|err| format!("Couldn't parse {}: {}", path, err))
+    }
+
+    // reads the config file named by --config, or the default config file if
+    // one exists, and then applies the remaining arguments over the top
+    pub fn from_args<I>(args: I) -> Result<Self, String>
+        where I: IntoIterator<Item = String>
+    {
+        let mut args: Vec<String> = args.into_iter().collect();
+
+        // --config has to be known before the file can be read, so it is
+        // taken out here rather than handled with the other options
+        let mut path = None;
+        while let Some(i) = args.iter().position(|arg| arg == "--config") {
+            if i + 1 == args.len() {
+                return Err("--config needs a value".to_string());
+            }
+            path = args.drain(i..i + 2).nth(1);
+        }
+        let mut config = match path {
+            Some(path) => Config::read_file(&path)?,
+            None => match fs::metadata(DEFAULT_PATH) {
+                Ok(_) => Config::read_file(DEFAULT_PATH)?,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    Config::default()
+                },
+                Err(err) => {
+                    let path = DEFAULT_PATH;
+                    return Err(format!("Couldn't read {}: {}", path, err));
+                },
+            },
+        };
+
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "server" | "-s" => {
+                    config.mode = Mode::Server;
+                    // the scenario can still be given the old way, as in
+                    // tactics -s SCENARIO
+                    let scenario = match args.peek() {
+                        Some(val) if !val.starts_with('-') => Some(val.clone()),
+                        _ => None,
+                    };
+                    if let Some(scenario) = scenario {
+                        args.next();
+                        config.scenario = scenario;
+                    }
+                },
+                "client" => config.mode = Mode::Client,
+                "--address" | "--port" | "--scenario"
+                    | "--name" | "--team" | "--plan" =>
+                {
+                    let val = args.next()
+                        .ok_or(format!("{} needs a value", arg))?;
+                    config.set(&arg, val)?;
+                },
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(self: &mut Self, arg: &str, val: String) -> Result<(), String> {
+        match arg {
+            "--address" => self.address = val,
+            "--port" => {
+                self.port = val.parse()
+                    .map_err(|_| format!("Invalid port {}", val))?;
+            },
+            "--scenario" => self.scenario = val,
+            "--name" => self.name = val,
+            "--plan" => self.plan = val,
+            "--team" => {
+                let team = val.parse()
+                    .map_err(|_| format!("Invalid team {}", val))?;
+                self.team = Some(team);
+            },
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // writes a config file for the test, and returns the arguments that
+    // point at it, so that a tactics.ron lying around isn't read instead
+    fn with_file(name: &str, text: &str, args: &[&str]) -> Vec<String> {
+        let name = format!("tactics-{}-{}", ::std::process::id(), name);
+        let path = ::std::env::temp_dir().join(name);
+        let path = path.to_string_lossy().into_owned();
+        fs::write(&path, text).unwrap();
+        let mut result = vec!["--config".to_string(), path];
+        result.extend(args.iter().map(|arg| arg.to_string()));
+        result
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let text = "(mode: Server, port: 6000, name: \"file\")";
+        let args = with_file("override", text, &["--name", "flag"]);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.mode, Mode::Server);
+        assert_eq!(config.port, 6000);
+        assert_eq!(config.name, "flag");
+        assert_eq!(config.address, Config::default().address);
+
+        let args = with_file("mode", text, &["client", "--port", "7000"]);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.mode, Mode::Client);
+        assert_eq!(config.port, 7000);
+    }
+
+    #[test]
+    fn scenario_after_server() {
+        let args = with_file("server", "()", &["-s", "4p", "--port", "1"]);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.mode, Mode::Server);
+        assert_eq!(config.scenario, "4p");
+        assert_eq!(config.port, 1);
+
+        let args = with_file("server-flag", "()", &["server", "--port", "1"]);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.scenario, Config::default().scenario);
+    }
+
+    #[test]
+    fn bad_arguments() {
+        let err = |name, args: &[&str]| {
+            Config::from_args(with_file(name, "()", args)).unwrap_err()
+        };
+        assert_eq!(err("port", &["--port", "70000"]), "Invalid port 70000");
+        assert_eq!(err("team", &["--team", "red"]), "Invalid team red");
+        assert_eq!(err("value", &["--name"]), "--name needs a value");
+        assert_eq!(err("unknown", &["--colour"]), "Unknown argument --colour");
+        let args = vec!["--config".to_string()];
+        assert_eq!(
+            Config::from_args(args).unwrap_err(),
+            "--config needs a value",
+        );
+    }
+}
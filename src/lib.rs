@@ -0,0 +1,81 @@
+#[cfg(feature = "gui")]
+extern crate piston_app;
+#[cfg(feature = "gui")]
+extern crate piston_window;
+extern crate vecmath;
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
+extern crate ron;
+
+
+pub mod model;
+pub mod path;
+pub mod save;
+pub mod config;
+pub mod server;
+pub mod client;
+pub mod script;
+pub mod regress;
+pub mod mapgen;
+pub mod server_app;
+#[cfg(feature = "gui")]
+pub mod client_app;
+
+pub mod prelude {
+    use std::collections::HashMap;
+
+    pub type EID = u32;
+    pub type TID = u32;
+
+    pub const NULL_ID: EID = EID::max_value();
+    // the team of things that aren't on anyone's side, like doors
+    pub const NULL_TEAM: TID = TID::max_value();
+
+    pub type Vec2 = ::vecmath::Vector2<f64>;
+    pub use vecmath::{vec2_scale, vec2_add, vec2_sub};
+
+    // a number of ticks, see model::Rules for how long a tick is
+    pub type Ticks = i64;
+
+    // simulation time is counted in whole ticks so that times can be compared
+    // exactly, and so that they can't drift or become NaN
+    #[derive(
+        Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default,
+        Serialize, Deserialize,
+    )]
+    pub struct Time(pub Ticks);
+
+    use std::ops;
+    impl ops::Add<Ticks> for Time {
+        type Output = Time;
+        fn add(self: Self, other: Ticks) -> Time {
+            Time(self.0 + other)
+        }
+    }
+    impl ops::AddAssign<Ticks> for Time {
+        fn add_assign(self: &mut Self, other: Ticks) {
+            self.0 += other;
+        }
+    }
+    impl ops::Sub<Ticks> for Time {
+        type Output = Time;
+        fn sub(self: Self, other: Ticks) -> Time {
+            Time(self.0 - other)
+        }
+    }
+    impl ops::Sub<Time> for Time {
+        type Output = Ticks;
+        fn sub(self: Self, other: Time) -> Ticks {
+            self.0 - other.0
+        }
+    }
+
+    pub fn empty_map<T, U: Default>(base: &HashMap<EID, T>) -> HashMap<EID, U> {
+        base.iter()
+            .map(|(&id, _)| (id, Default::default()))
+            .collect()
+    }
+}
@@ -1,90 +1,150 @@
+#[cfg(feature = "gui")]
 extern crate piston_app;
-extern crate piston_window;
-extern crate vecmath;
+extern crate tactics;
 
-#[macro_use]
-extern crate serde_derive;
-extern crate serde;
-extern crate bincode;
-extern crate ron;
+use std::path::Path;
 
+use tactics::config::{self, Config, Mode};
+use tactics::mapgen;
+use tactics::regress;
+use tactics::save;
+use tactics::server_app;
+#[cfg(feature = "gui")]
+use tactics::client_app;
 
-mod model;
-mod path;
-mod save;
-mod server;
-mod client;
-mod server_app;
-mod client_app;
-
-pub mod prelude {
-    use std::collections::HashMap;
-
-    pub type EID = u32;
-    pub type TID = u32;
-
-    pub const NULL_ID: EID = EID::max_value();
-
-    pub type Vec2 = ::vecmath::Vector2<f64>;
-    pub use vecmath::{vec2_scale, vec2_add, vec2_sub};
-
-    // should use NotNaN crate
-    #[derive(Clone, Copy, Serialize, Deserialize)]
-    pub struct Time(pub f64);
+fn main() {
+    let args: Vec<String> = ::std::env::args().skip(1).collect();
+    if args.get(0).map(|arg| &**arg) == Some("regress") {
+        run_regress(&args[1..]);
+        return;
+    }
+    if args.get(0).map(|arg| &**arg) == Some("mapgen") {
+        run_mapgen(&args[1..]);
+        return;
+    }
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, config::USAGE);
+            ::std::process::exit(2);
+        },
+    };
+    match config.mode {
+        Mode::Server => run_server(config),
+        Mode::Client => run_client(config),
+    }
+}
 
-    use std::cmp;
-    impl PartialEq for Time {
-        fn eq(self: &Self, other: &Self) -> bool {
-            self.0 == other.0
+// usage: tactics regress [--bless] CASE...
+fn run_regress(args: &[String]) {
+    let bless = args.iter().any(|arg| arg == "--bless");
+    let mut failures = 0;
+    for case in args.iter().filter(|&arg| arg != "--bless") {
+        match regress::run_case(Path::new(case), bless) {
+            Ok(regress::Outcome::Pass) => println!("ok      {}", case),
+            Ok(regress::Outcome::Blessed) => println!("blessed {}", case),
+            Ok(regress::Outcome::Fail(diff)) => {
+                println!("FAILED  {}\n{}", case, diff);
+                failures += 1;
+            },
+            Err(msg) => {
+                println!("ERROR   {}: {}", case, msg);
+                failures += 1;
+            },
         }
     }
-    impl Eq for Time {}
+    if failures > 0 {
+        println!("{} case(s) failed", failures);
+        ::std::process::exit(1);
+    }
+}
 
-    impl PartialOrd for Time {
-        fn partial_cmp(self: &Self, other: &Self) -> Option<cmp::Ordering> {
-            PartialOrd::partial_cmp(&self.0, &other.0)
-        }
+// usage: tactics mapgen (NAME | random SEED TEAMS) [OUTPUT]
+fn run_mapgen(args: &[String]) {
+    let (scenario, output) = match args.get(0).map(|arg| &**arg) {
+        Some("random") => (random_map(&args[1..]), args.get(3)),
+        Some(name) => (mapgen::generate(name), args.get(1)),
+        None => (Err("Expected the name of a map".to_string()), None),
+    };
+    let scenario = match scenario {
+        Ok(scenario) => scenario,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, config::USAGE);
+            ::std::process::exit(2);
+        },
+    };
+    let text = save::write_scenario(&scenario);
+    match output {
+        Some(path) => {
+            if let Err(err) = ::std::fs::write(path, text) {
+                eprintln!("Couldn't write {}: {}", path, err);
+                ::std::process::exit(1);
+            }
+        },
+        None => println!("{}", text),
     }
+}
 
-    impl Ord for Time {
-        fn cmp(self: &Self, other: &Self) -> cmp::Ordering {
-            cmp::PartialOrd::partial_cmp(&self.0, &other.0)
-                .expect("Got NaN as time...")
-        }
+fn random_map(args: &[String]) -> Result<save::Scenario, String> {
+    if args.len() < 2 {
+        return Err("Expected a seed and a number of teams".to_string());
     }
+    let seed = args[0].parse()
+        .map_err(|_| format!("Expected a seed, got {}", args[0]))?;
+    let teams = args[1].parse()
+        .map_err(|_| format!("Expected a number of teams, got {}", args[1]))?;
+    mapgen::random(seed, teams)
+}
 
-    pub fn empty_map<T, U: Default>(base: &HashMap<EID, T>) -> HashMap<EID, U> {
-        base.iter()
-            .map(|(&id, _)| (id, Default::default()))
-            .collect()
+fn run_server(config: Config) {
+    let ip = (&*config.address, config.port);
+    let mut app = server_app::ServerApp::new(ip);
+    println!("Hosting {} on {}:{}", config.scenario, ip.0, ip.1);
+    let mut loaded = false;
+    loop {
+        match app.spawn_instance(&config.scenario) {
+            Ok(server) => {
+                loaded = true;
+                server.run_async();
+            },
+            Err(err) if !loaded => {
+                eprintln!("Couldn't load scenario, {}", err);
+                ::std::process::exit(1);
+            },
+            // games that are already running keep going, and the next one
+            // starts once the scenario has been fixed
+            Err(err) => {
+                eprintln!("Couldn't reload scenario, {}", err);
+                wait_for_change(&config.scenario);
+            },
+        }
     }
 }
 
-fn readln() -> String {
-    let mut buffer = String::new();
-    let stdin = ::std::io::stdin();
+fn wait_for_change(path: &str) {
+    let modified = |path| {
+        ::std::fs::metadata(path).and_then(|data| data.modified()).ok()
+    };
+    let old = modified(path);
+    while modified(path) == old {
+        ::std::thread::sleep(::std::time::Duration::from_secs(1));
+    }
+}
 
-    stdin.read_line(&mut buffer).expect("Stdin failed");
-    buffer.split_whitespace().next().expect("No text entered").to_string()
+#[cfg(feature = "gui")]
+fn run_client(config: Config) {
+    let ip = (&*config.address, config.port);
+    let app = client_app::ClientApp::new(
+        ip,
+        config.name,
+        config.team,
+        config.plan,
+    );
+    piston_app::run_until_escape(app);
 }
 
-fn main() {
-    let mut args = std::env::args();
-    let _this_app = args.next();
-    let fst = args.next();
-    println!("Enter ip to connect to: ");
-    let ip = readln();
-    if fst == Some("-s".to_string()) {
-        let path = args.next().unwrap_or("map".to_string());
-        let mut app = server_app::ServerApp::new(&ip);
-        loop {
-            let server = app.spawn_instance(&path);
-            server.run_async();
-        }
-    } else {
-        println!("Enter a name: ");
-        let name = readln();
-        let app = client_app::ClientApp::new(&ip, name);
-        piston_app::run_until_escape(app);
-    }
+#[cfg(not(feature = "gui"))]
+fn run_client(_config: Config) {
+    eprintln!("This build has no client, rebuild with `--features gui`");
+    ::std::process::exit(1);
 }
@@ -0,0 +1,593 @@
+// a port of the haskell scripts in mapgen/, for building scenarios out of
+// shapes and symmetries, and writing them out for the server to read
+
+use prelude::*;
+
+use model;
+use path;
+use save;
+
+// which teams the units in a mirrored copy go on, compared to the units that
+// they were copied from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Teams {
+    // the same team
+    Shared,
+    // the next team round, so with two teams they swap
+    Rotated,
+    // a new team, so that the copy has teams of its own
+    Separate,
+}
+
+fn team_count(scenario: &save::Scenario) -> TID {
+    scenario.units
+        .iter()
+        .map(|unit| unit.team + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+fn copy_team(teams: Teams, count: TID, team: TID) -> TID {
+    match teams {
+        Teams::Shared => team,
+        Teams::Rotated => (team + 1) % count,
+        Teams::Separate => team + count,
+    }
+}
+
+pub fn unit(team: TID, pos: Vec2) -> save::Unit {
+    save::Unit {
+        team,
+        pos: (pos[0], pos[1]),
+        weapon: model::Weapon::Gun,
+        loadout: Vec::new(),
+        name: None,
+        facing: 0.0,
+    }
+}
+
+pub fn rect(p1: Vec2, p2: Vec2) -> Vec<Vec2> {
+    let [x1, y1] = p1;
+    let [x2, y2] = p2;
+    vec![[x1, y1], [x2, y1], [x2, y2], [x1, y2]]
+}
+
+pub fn square(r: f64, centre: Vec2) -> Vec<Vec2> {
+    let [x, y] = centre;
+    rect([x - r, y - r], [x + r, y + r])
+}
+
+// a wall of thickness 2r from one point to another, with square ends
+pub fn line(r: f64, c1: Vec2, c2: Vec2) -> Vec<Vec2> {
+    if c1[0] > c2[0] {
+        return line(r, c2, c1);
+    }
+    if c1[1] > c2[1] {
+        let neg = |p| reflect_vert(0.0, p);
+        return line(r, neg(c1), neg(c2)).into_iter().map(neg).collect();
+    }
+    let s1 = square(r, c1);
+    let s2 = square(r, c2);
+    vec![s1[0], s1[1], s2[1], s2[2], s2[3], s1[3]]
+}
+
+pub fn reflect_horiz(rx: f64, p: Vec2) -> Vec2 {
+    [rx + rx - p[0], p[1]]
+}
+
+pub fn reflect_vert(ry: f64, p: Vec2) -> Vec2 {
+    [p[0], ry + ry - p[1]]
+}
+
+// reflects across the line through r that goes down and to the left
+pub fn reflect_diag(r: Vec2, p: Vec2) -> Vec2 {
+    [r[0] + r[1] - p[1], r[1] + r[0] - p[0]]
+}
+
+pub fn turn_half(centre: Vec2, p: Vec2) -> Vec2 {
+    reflect_horiz(centre[0], reflect_vert(centre[1], p))
+}
+
+pub fn turn_quarter(centre: Vec2, p: Vec2) -> Vec2 {
+    let [cx, cy] = centre;
+    [cx + cy - p[1], cy + p[0] - cx]
+}
+
+// a copy of the scenario with every point moved, and units facing the way
+// that they would after being moved
+pub fn map_points<F>(scenario: &save::Scenario, f: F, teams: Teams)
+    -> save::Scenario
+    where F: Fn(Vec2) -> Vec2
+{
+    let count = team_count(scenario);
+    let point = |(x, y): (f64, f64)| {
+        let [x, y] = f([x, y]);
+        (x, y)
+    };
+    let mut result = scenario.clone();
+    for unit in &mut result.units {
+        let pos = [unit.pos.0, unit.pos.1];
+        let angle = unit.facing.to_radians();
+        let ahead = vec2_add(pos, [angle.cos(), angle.sin()]);
+        let disp = vec2_sub(f(ahead), f(pos));
+        unit.facing = model::direction(disp).to_degrees();
+        unit.pos = point(unit.pos);
+        unit.team = copy_team(teams, count, unit.team);
+    }
+    for poly in &mut result.map {
+        *poly = poly.iter().map(|&p| f(p)).collect();
+    }
+    for poly in &mut result.polys {
+        poly.points = poly.points.iter().map(|&p| f(p)).collect();
+    }
+    for door in &mut result.doors {
+        door.from = point(door.from);
+        door.to = point(door.to);
+    }
+    result
+}
+
+// the scenario along with a moved copy of it
+pub fn mirror<F>(scenario: save::Scenario, f: F, teams: Teams)
+    -> save::Scenario
+    where F: Fn(Vec2) -> Vec2
+{
+    let copy = map_points(&scenario, f, teams);
+    let mut result = scenario;
+    result.units.extend(copy.units);
+    result.map.extend(copy.map);
+    result.polys.extend(copy.polys);
+    result.doors.extend(copy.doors);
+    result
+}
+
+pub fn symm_horiz(scenario: save::Scenario, rx: f64, teams: Teams)
+    -> save::Scenario
+{
+    mirror(scenario, |p| reflect_horiz(rx, p), teams)
+}
+
+pub fn symm_vert(scenario: save::Scenario, ry: f64, teams: Teams)
+    -> save::Scenario
+{
+    mirror(scenario, |p| reflect_vert(ry, p), teams)
+}
+
+pub fn symm_diag(scenario: save::Scenario, r: Vec2, teams: Teams)
+    -> save::Scenario
+{
+    mirror(scenario, |p| reflect_diag(r, p), teams)
+}
+
+pub fn symm_quad(scenario: save::Scenario, r: Vec2, teams: Teams)
+    -> save::Scenario
+{
+    symm_horiz(symm_vert(scenario, r[1], teams), r[0], teams)
+}
+
+pub fn symm_oct(scenario: save::Scenario, r: Vec2, teams: Teams)
+    -> save::Scenario
+{
+    symm_quad(symm_diag(scenario, r, teams), r, teams)
+}
+
+pub fn symm_half(scenario: save::Scenario, centre: Vec2, teams: Teams)
+    -> save::Scenario
+{
+    mirror(scenario, |p| turn_half(centre, p), teams)
+}
+
+pub fn symm_quarter(scenario: save::Scenario, centre: Vec2, teams: Teams)
+    -> save::Scenario
+{
+    let turned = mirror(scenario, |p| turn_quarter(centre, p), teams);
+    symm_half(turned, centre, teams)
+}
+
+// mapgen/Demo.hs
+pub fn demo() -> save::Scenario {
+    let centre = [30.0, 30.0];
+    let shields = save::Scenario {
+        map: vec![line(0.25, [10.25, 20.25], [17.75, 27.75])],
+        ..Default::default()
+    };
+    let mut quadrant = symm_diag(shields, centre, Teams::Shared);
+    quadrant.units = vec![unit(0, [5.0, 30.0])];
+    quadrant.map.extend(vec![
+        // box
+        rect([29.0, 29.0], [30.0, 30.0]),
+        // room wall
+        rect([20.0, 20.0], [27.0, 21.0]),
+        rect([20.0, 20.0], [21.0, 27.0]),
+        // corner wall
+        line(0.5, [2.5, 2.5], [15.5, 15.5]),
+    ]);
+    let mut level = symm_quarter(quadrant, centre, Teams::Shared);
+    // Demo.hs puts every unit on team 0, and the file was split into two
+    // teams by hand, with the units a quarter turn apart on different teams
+    for (i, unit) in level.units.iter_mut().enumerate() {
+        unit.team = i as TID % 2;
+    }
+    save::Scenario {
+        title: "Demo".to_string(),
+        description: "Two units a side behind shield walls.".to_string(),
+        players: Some(2),
+        bounds: Some([[0.0, 0.0], [60.0, 60.0]]),
+        ..face_forward(level)
+    }
+}
+
+// mapgen/Conflict.hs
+pub fn conflict() -> save::Scenario {
+    let centre = [30.0, 30.0];
+    let corners = [
+        [18.5, 37.0],
+        [13.0, 37.0],
+        [13.0, 23.0],
+        [27.0, 23.0],
+        [27.0, 37.0],
+        [21.5, 37.0],
+    ];
+    let mut map: Vec<_> = corners
+        .windows(2)
+        .map(|pair| line(0.5, pair[0], pair[1]))
+        .collect();
+    map.push(square(1.0, [20.0, 30.0]));
+    let half = save::Scenario {
+        units: vec![unit(0, [20.0, 28.0]), unit(1, [20.0, 38.0])],
+        map,
+        ..Default::default()
+    };
+    save::Scenario {
+        title: "Conflict".to_string(),
+        description: "Two units a side among a cluster of boxes.".to_string(),
+        players: Some(2),
+        bounds: Some([[0.0, 0.0], [60.0, 60.0]]),
+        ..face_forward(symm_half(half, centre, Teams::Rotated))
+    }
+}
+
+// the haskell scripts have no facings, so the units in the files that they
+// wrote all face along the x axis, however they were moved
+fn face_forward(mut scenario: save::Scenario) -> save::Scenario {
+    for unit in &mut scenario.units {
+        unit.facing = 0.0;
+    }
+    scenario
+}
+
+pub fn generate(name: &str) -> Result<save::Scenario, String> {
+    match name {
+        "demo" => Ok(demo()),
+        "conflict" => Ok(conflict()),
+        _ => Err(format!("There is no map called {}", name)),
+    }
+}
+
+// splitmix64, so that the same seed always gives the same map without
+// depending on a particular random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(self: &mut Self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn range(self: &mut Self, lo: f64, hi: f64) -> f64 {
+        let unit = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        lo + unit * (hi - lo)
+    }
+
+    fn point(self: &mut Self, lo: Vec2, hi: Vec2) -> Vec2 {
+        [self.range(lo[0], hi[0]), self.range(lo[1], hi[1])]
+    }
+}
+
+const ARENA_SIZE: f64 = 60.0;
+const UNITS_PER_TEAM: usize = 2;
+// how many tries the generator gets before giving up on a seed
+const ATTEMPTS: usize = 200;
+// how many shapes an attempt can throw away for crowding the spawn before it
+// gives up and leaves it to the next attempt
+const CANDIDATES: usize = 100;
+
+// scatters walls and cover over one sector of the arena, and then turns
+// copies of it around the centre so that each team gets the same sector
+pub fn random(seed: u64, teams: TID) -> Result<save::Scenario, String> {
+    if teams != 2 && teams != 4 {
+        return Err(format!("Can't generate a map for {} teams", teams));
+    }
+    let mut rng = Rng(seed);
+    for _ in 0..ATTEMPTS {
+        match random_attempt(&mut rng, teams) {
+            Some(scenario) if is_fair(&scenario) => return Ok(scenario),
+            _ => (),
+        }
+    }
+    Err(format!("Couldn't generate a fair map from seed {}", seed))
+}
+
+fn random_attempt(rng: &mut Rng, teams: TID) -> Option<save::Scenario> {
+    let centre = [ARENA_SIZE / 2.0, ARENA_SIZE / 2.0];
+    // the sector is the left half for 2 teams, and the top left quarter for
+    // 4 teams, with spawns along its outer edge
+    let (hi, spawn_lo, spawn_hi, objects) = if teams == 2 {
+        ([centre[0], ARENA_SIZE], [3.0, 10.0], [8.0, 50.0], 8)
+    } else {
+        ([centre[0], centre[1]], [3.0, 3.0], [12.0, 12.0], 5)
+    };
+    let spawn = rng.point(spawn_lo, spawn_hi);
+    let mut units = Vec::new();
+    for i in 0..UNITS_PER_TEAM {
+        units.push(unit(0, vec2_add(spawn, [0.0, 2.0 * i as f64])));
+    }
+
+    let mut sector = save::Scenario {
+        units,
+        ..Default::default()
+    };
+    let mut candidates = 0;
+    while sector.map.len() + sector.polys.len() < objects {
+        if candidates == CANDIDATES {
+            return None;
+        }
+        candidates += 1;
+        let poly = random_poly(rng, [1.0, 1.0], hi);
+        // leave room around the spawn
+        let mut map = path::Map::new();
+        for trig in save::triangulate(&poly.points) {
+            map.push((trig, poly.material, 0));
+        }
+        let blocked = sector.units.iter().any(|unit| {
+            let pos = [unit.pos.0, unit.pos.1];
+            path::near_wall(&map, 0, pos, 2.0)
+        });
+        if blocked {
+            continue;
+        }
+        if poly.material == path::Material::Wall {
+            sector.map.push(poly.points);
+        } else {
+            sector.polys.push(poly);
+        }
+    }
+
+    let scenario = if teams == 2 {
+        symm_half(sector, centre, Teams::Separate)
+    } else {
+        let turned = mirror(
+            sector,
+            |p| turn_quarter(centre, p),
+            Teams::Separate,
+        );
+        symm_half(turned, centre, Teams::Separate)
+    };
+    Some(save::Scenario {
+        title: "Random".to_string(),
+        players: Some(teams as usize),
+        bounds: Some([[0.0, 0.0], [ARENA_SIZE, ARENA_SIZE]]),
+        ..scenario
+    })
+}
+
+// mostly walls and boxes, with some low cover and glass
+fn random_poly(rng: &mut Rng, lo: Vec2, hi: Vec2) -> save::Poly {
+    let kind = rng.range(0.0, 1.0);
+    let material = if kind < 0.7 {
+        path::Material::Wall
+    } else if kind < 0.9 {
+        path::Material::LowCover
+    } else {
+        path::Material::Glass
+    };
+    let start = rng.point(lo, hi);
+    let points = if kind < 0.3 {
+        square(rng.range(0.5, 1.5), start)
+    } else {
+        let angle = rng.range(0.0, 2.0 * ::std::f64::consts::PI);
+        let len = rng.range(4.0, 12.0);
+        let end = vec2_add(start, [angle.cos() * len, angle.sin() * len]);
+        let end = [end[0].max(lo[0]).min(hi[0]), end[1].max(lo[1]).min(hi[1])];
+        line(rng.range(0.25, 0.5), start, end)
+    };
+    save::Poly {
+        material,
+        points,
+        hp: None,
+    }
+}
+
+// every unit can walk to every other unit, and no unit starts off able to see
+// an enemy
+fn is_fair(scenario: &save::Scenario) -> bool {
+    let bounds = scenario.bounds.unwrap();
+    let mut map = path::Map::new();
+    for points in &scenario.map {
+        for trig in save::triangulate(points) {
+            map.push((trig, path::Material::Wall, NULL_ID));
+        }
+    }
+    for poly in &scenario.polys {
+        for trig in save::triangulate(&poly.points) {
+            map.push((trig, poly.material, NULL_ID));
+        }
+    }
+    let spawns: Vec<Vec2> = scenario.units
+        .iter()
+        .map(|unit| [unit.pos.0, unit.pos.1])
+        .collect();
+    if spawns.iter().any(|&pos| path::unit_is_in_wall(&map, pos)) {
+        return false;
+    }
+    if !path::reachable(&map, bounds, spawns[0], &spawns[1..], 1.0) {
+        return false;
+    }
+    for one in &scenario.units {
+        for other in &scenario.units {
+            let p1 = [one.pos.0, one.pos.1];
+            let p2 = [other.pos.0, other.pos.1];
+            if one.team != other.team && path::unit_can_see_pos(&map, p1, p2) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: Vec2 = [12.0, 7.5];
+    const C: Vec2 = [30.0, 30.0];
+
+    #[test]
+    fn reflections_undo_themselves() {
+        assert_eq!(reflect_horiz(30.0, P), [48.0, 7.5]);
+        assert_eq!(reflect_vert(30.0, P), [12.0, 52.5]);
+        assert_eq!(reflect_horiz(30.0, reflect_horiz(30.0, P)), P);
+        assert_eq!(reflect_vert(30.0, reflect_vert(30.0, P)), P);
+        assert_eq!(reflect_diag(C, reflect_diag(C, P)), P);
+        // points on the diagonal stay put
+        assert_eq!(reflect_diag(C, [40.0, 20.0]), [40.0, 20.0]);
+    }
+
+    #[test]
+    fn turns_add_up() {
+        let quarter = |p| turn_quarter(C, p);
+        assert_eq!(quarter(quarter(P)), turn_half(C, P));
+        assert_eq!(quarter(quarter(quarter(quarter(P)))), P);
+        assert_eq!(turn_half(C, P), [48.0, 52.5]);
+    }
+
+    fn two_units() -> save::Scenario {
+        save::Scenario {
+            units: vec![unit(0, [5.0, 30.0]), unit(1, [10.0, 30.0])],
+            map: vec![square(1.0, [20.0, 20.0])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn copies_face_the_other_way() {
+        let turn = |p| turn_half(C, p);
+        let turned = map_points(&two_units(), turn, Teams::Shared);
+        assert_eq!(turned.units[0].pos, (55.0, 30.0));
+        assert_eq!(turned.units[0].facing, 180.0);
+        let flip = |p| reflect_vert(C[1], p);
+        let flipped = map_points(&two_units(), flip, Teams::Shared);
+        assert_eq!(flipped.units[0].facing, 0.0);
+    }
+
+    #[test]
+    fn copies_change_teams() {
+        let teams = |scenario: save::Scenario| -> Vec<TID> {
+            scenario.units.iter().map(|unit| unit.team).collect()
+        };
+        let shared = symm_horiz(two_units(), 30.0, Teams::Shared);
+        assert_eq!(teams(shared), vec![0, 1, 0, 1]);
+        let rotated = symm_horiz(two_units(), 30.0, Teams::Rotated);
+        assert_eq!(teams(rotated), vec![0, 1, 1, 0]);
+        let separate = symm_horiz(two_units(), 30.0, Teams::Separate);
+        assert_eq!(teams(separate), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn symmetries_multiply() {
+        let count = |scenario: save::Scenario| scenario.map.len();
+        assert_eq!(count(symm_quad(two_units(), C, Teams::Shared)), 4);
+        assert_eq!(count(symm_oct(two_units(), C, Teams::Shared)), 8);
+        assert_eq!(count(symm_quarter(two_units(), C, Teams::Shared)), 4);
+    }
+
+    // the committed scenarios are the output of these generators
+    fn committed(name: &str) -> save::Scenario {
+        let path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let text = ::std::fs::read_to_string(&path).unwrap();
+        ::ron::de::from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn demo_matches_file() {
+        assert_eq!(demo(), committed("demo"));
+    }
+
+    #[test]
+    fn conflict_matches_file() {
+        assert_eq!(conflict(), committed("conflict"));
+    }
+
+    #[test]
+    fn seeds_repeat() {
+        assert_eq!(random(7, 2), random(7, 2));
+        assert_eq!(random(7, 4), random(7, 4));
+        assert!(random(7, 2) != random(8, 2));
+        assert!(random(7, 3).is_err());
+    }
+
+    fn close(p1: Vec2, p2: Vec2) -> bool {
+        vecmath::vec2_len(vec2_sub(p1, p2)) < 1e-9
+    }
+
+    // every shape and unit has a moved copy, with the unit on another team
+    fn symmetric<F>(scenario: &save::Scenario, f: F) -> bool
+        where F: Fn(Vec2) -> Vec2
+    {
+        let moved = |points: &Vec<Vec2>| -> Vec<Vec2> {
+            points.iter().map(|&p| f(p)).collect()
+        };
+        let same = |a: &Vec<Vec2>, b: &Vec<Vec2>| {
+            a.len() == b.len() && a.iter().zip(b).all(|(&p, &q)| close(p, q))
+        };
+        let walls = scenario.map.iter().all(|points| {
+            scenario.map.iter().any(|other| same(&moved(points), other))
+        });
+        let polys = scenario.polys.iter().all(|poly| {
+            scenario.polys.iter().any(|other| {
+                other.material == poly.material
+                    && same(&moved(&poly.points), &other.points)
+            })
+        });
+        let units = scenario.units.iter().all(|unit| {
+            let pos = f([unit.pos.0, unit.pos.1]);
+            scenario.units.iter().any(|other| {
+                other.team != unit.team
+                    && close(pos, [other.pos.0, other.pos.1])
+            })
+        });
+        walls && polys && units
+    }
+
+    #[test]
+    fn random_maps_are_fair() {
+        for &teams in &[2, 4] {
+            for seed in 1..4 {
+                let scenario = random(seed, teams).unwrap();
+                assert!(is_fair(&scenario));
+                assert!(symmetric(&scenario, |p| turn_half(C, p)));
+                if teams == 4 {
+                    assert!(symmetric(&scenario, |p| turn_quarter(C, p)));
+                }
+
+                let name = format!(
+                    "tactics-{}-random-{}-{}",
+                    ::std::process::id(),
+                    seed,
+                    teams,
+                );
+                let path = ::std::env::temp_dir().join(name);
+                let path = path.to_string_lossy().into_owned();
+                ::std::fs::write(&path, save::write_scenario(&scenario))
+                    .unwrap();
+                let (unit_teams, _, _, _, _, info) =
+                    save::read_scenario(&path).unwrap();
+                assert_eq!(unit_teams.len(), 2 * teams as usize);
+                assert_eq!(info.players, Some(teams as usize));
+            }
+        }
+    }
+}
@@ -1,34 +1,739 @@
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::iter;
+use vecmath;
 
 use prelude::*;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+use path;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum Weapon {
     Gun,
+    Shotgun,
+    Sniper,
+    Melee,
+}
+
+pub const WEAPONS: [Weapon; 4] = [
+    Weapon::Gun,
+    Weapon::Shotgun,
+    Weapon::Sniper,
+    Weapon::Melee,
+];
+
+// distances are in map units, and times and speeds are in seconds rather than
+// ticks so that they don't depend on the tick length
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct WeaponStats {
+    // how long an unhurt target has to be held under fire before it dies, for
+    // the shotgun this is at the edge of its range, and it kills faster the
+    // closer the target was when it started firing
+    pub kill_time: f64,
+    pub range: f64,
+    // fastest the shooter can move while firing
+    pub fire_speed: f64,
+    pub needs_los: bool,
+    // seconds it takes to ready this weapon after switching to it
+    pub swap_time: f64,
+    // rounds that the weapon holds at once, weapons that hold none don't
+    // need ammunition at all
+    pub mag_size: u32,
+    // spare rounds that a unit carrying the weapon starts with
+    pub reserve: u32,
+    // rounds per second while shooting or suppressing
+    pub fire_rate: f64,
+    pub reload_time: f64,
+}
+
+// rules files can leave out any of a weapon's stats, and get the weapon's
+// default for them, so that files written before a stat existed still load
+#[derive(Deserialize)]
+struct PartialStats {
+    #[serde(default, deserialize_with = "some")]
+    kill_time: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    range: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    fire_speed: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    needs_los: Option<bool>,
+    #[serde(default, deserialize_with = "some")]
+    swap_time: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    mag_size: Option<u32>,
+    #[serde(default, deserialize_with = "some")]
+    reserve: Option<u32>,
+    #[serde(default, deserialize_with = "some")]
+    fire_rate: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    reload_time: Option<f64>,
+}
+
+fn some<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+    where D: ::serde::Deserializer<'de>,
+          T: ::serde::Deserialize<'de>,
+{
+    ::serde::Deserialize::deserialize(de).map(Some)
+}
+
+fn read_weapons<'de, D>(de: D) -> Result<HashMap<Weapon, WeaponStats>, D::Error>
+    where D: ::serde::Deserializer<'de>,
+{
+    let partial: HashMap<Weapon, PartialStats> =
+        ::serde::Deserialize::deserialize(de)?;
+    let mut weapons = HashMap::new();
+    for (weapon, stats) in partial {
+        let default = weapon.default_stats();
+        weapons.insert(weapon, WeaponStats {
+            kill_time: stats.kill_time.unwrap_or(default.kill_time),
+            range: stats.range.unwrap_or(default.range),
+            fire_speed: stats.fire_speed.unwrap_or(default.fire_speed),
+            needs_los: stats.needs_los.unwrap_or(default.needs_los),
+            swap_time: stats.swap_time.unwrap_or(default.swap_time),
+            mag_size: stats.mag_size.unwrap_or(default.mag_size),
+            reserve: stats.reserve.unwrap_or(default.reserve),
+            fire_rate: stats.fire_rate.unwrap_or(default.fire_rate),
+            reload_time: stats.reload_time.unwrap_or(default.reload_time),
+        });
+    }
+    Ok(weapons)
+}
+
+impl Weapon {
+    pub fn default_stats(self: Self) -> WeaponStats {
+        match self {
+            Weapon::Gun => WeaponStats {
+                kill_time: 5.0,
+                range: 100.0,
+                fire_speed: 0.0,
+                needs_los: true,
+                swap_time: 1.0,
+                mag_size: 30,
+                reserve: 90,
+                fire_rate: 4.0,
+                reload_time: 2.0,
+            },
+            Weapon::Shotgun => WeaponStats {
+                kill_time: 4.0,
+                range: 10.0,
+                fire_speed: 0.5,
+                needs_los: true,
+                swap_time: 1.0,
+                mag_size: 6,
+                reserve: 24,
+                fire_rate: 1.0,
+                reload_time: 3.0,
+            },
+            Weapon::Sniper => WeaponStats {
+                kill_time: 8.0,
+                range: 200.0,
+                fire_speed: 0.0,
+                needs_los: true,
+                swap_time: 2.0,
+                mag_size: 5,
+                reserve: 15,
+                fire_rate: 0.5,
+                reload_time: 3.0,
+            },
+            Weapon::Melee => WeaponStats {
+                kill_time: 1.0,
+                range: 1.5,
+                fire_speed: 1.0,
+                needs_los: true,
+                swap_time: 0.5,
+                mag_size: 0,
+                reserve: 0,
+                fire_rate: 0.0,
+                reload_time: 0.0,
+            },
+        }
+    }
+
+    // melee weapons can only be used on a target that is right there
+    pub fn allows(self: Self, action: Action) -> bool {
+        match action {
+            Action::Suppress | Action::Overwatch => self != Weapon::Melee,
+            _ => true,
+        }
+    }
+}
+
+// most weapons that a unit can carry, including the one in its hands
+pub const LOADOUT_SIZE: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Slot {
+    pub weapon: Weapon,
+    // rounds ready to fire, and spare rounds to reload with
+    pub mag: u32,
+    pub reserve: u32,
+}
+
+pub type Loadout = [Option<Slot>; LOADOUT_SIZE];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum MoveMode {
+    Sprint,
+    Walk,
+    Crouch,
+}
+
+pub const MOVE_MODES: [MoveMode; 3] = [
+    MoveMode::Sprint,
+    MoveMode::Walk,
+    MoveMode::Crouch,
+];
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MoveModeStats {
+    // in distance per second
+    pub speed: f64,
+    // scales the distance that the unit can be seen from
+    pub visibility: f64,
+    // scales how quickly the unit's shots do damage
+    pub accuracy: f64,
+}
+
+impl MoveMode {
+    pub fn default_stats(self: Self) -> MoveModeStats {
+        match self {
+            MoveMode::Sprint => MoveModeStats {
+                speed: 2.0,
+                visibility: 1.0,
+                accuracy: 0.5,
+            },
+            MoveMode::Walk => MoveModeStats {
+                speed: 1.0,
+                visibility: 1.0,
+                accuracy: 1.0,
+            },
+            MoveMode::Crouch => MoveModeStats {
+                speed: 0.5,
+                visibility: 0.25,
+                accuracy: 1.25,
+            },
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Action {
     Mobile,
     Shoot,
+    // firing along the line to target_loc, at any enemy that crosses it
+    Suppress,
+    // watching for enemies to shoot at, facing target_loc
+    Overwatch,
+    // winding up to throw a grenade at target_loc
+    Throw,
+    // getting `weapon` ready to use
+    Switch,
+    // refilling the magazine of `weapon` from its reserve
+    Reload,
     Dead,
+    // a grenade in flight or on the ground, which explodes at the given time
+    Grenade(Time),
+    // what is left of a grenade after it explodes
+    Detonated,
+    // opening or closing the door given by target_id
+    Operate,
+    // doors are walls that run from pos to target_loc while they are closed
+    Open,
+    Closed,
+    // walls that can be destroyed, whose health is their hit points, which
+    // are measured in units of FULL_HEALTH
+    Wall,
+    Rubble,
+}
+
+impl Action {
+    // grenades, doors and walls share UnitState with units, but can't be
+    // given commands
+    pub fn is_unit(self: Self) -> bool {
+        match self {
+            Action::Grenade(_) | Action::Detonated => false,
+            Action::Open | Action::Closed => false,
+            Action::Wall | Action::Rubble => false,
+            _ => true,
+        }
+    }
+
+    // whether the entity can be shot at and caught in blasts
+    pub fn can_be_hurt(self: Self) -> bool {
+        match self {
+            Action::Dead => false,
+            Action::Wall => true,
+            _ => self.is_unit(),
+        }
+    }
+
+    pub fn is_door(self: Self) -> bool {
+        self == Action::Open || self == Action::Closed
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub struct UnitState {
+    pub team: TID,
     pub pos: Vec2,
     pub vel: Vec2,
+    // in radians from the x axis towards the y axis, which is clockwise on
+    // screen since y points down, and radians per tick
+    pub facing: f64,
+    pub spin: f64,
+    // the way the unit last moved, which it keeps until it moves again
+    pub mode: MoveMode,
+    // the weapon in the unit's hands, which is always one of its loadout
     pub weapon: Weapon,
+    pub loadout: Loadout,
     pub action: Action,
     pub target_loc: Vec2,
     pub target_id: EID,
+    // between 0 and FULL_HEALTH, as of `time`, damage taken since then from
+    // units that are still shooting is worked out by the server
+    pub health: f64,
 
-    pub time: f64,
+    pub time: Time,
     pub id: EID,
 }
 
+pub const FULL_HEALTH: f64 = 1.0;
+
+impl UnitState {
+    pub fn slot(self: &Self, weapon: Weapon) -> Option<Slot> {
+        self.loadout
+            .iter()
+            .filter_map(|&slot| slot)
+            .find(|slot| slot.weapon == weapon)
+    }
+
+    pub fn carries(self: &Self, weapon: Weapon) -> bool {
+        self.slot(weapon).is_some()
+    }
+
+    // whether the unit is using up ammunition
+    pub fn is_firing(self: &Self) -> bool {
+        match self.action {
+            Action::Shoot | Action::Suppress => true,
+            _ => false,
+        }
+    }
+}
+
+pub fn direction(disp: Vec2) -> f64 {
+    disp[1].atan2(disp[0])
+}
+
+// the smallest turn that takes one angle to the other, between -pi and pi
+pub fn angle_between(from: f64, to: f64) -> f64 {
+    use std::f64::consts::PI;
+    let turn = (to - from) % (2.0 * PI);
+    if turn > PI {
+        turn - 2.0 * PI
+    } else if turn < -PI {
+        turn + 2.0 * PI
+    } else {
+        turn
+    }
+}
+
+// rules that stay the same for a whole scenario, shared by the server and
+// all of the clients
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    // in seconds
+    pub tick_length: f64,
+    // weapons that are left out get their default stats
+    #[serde(deserialize_with = "read_weapons")]
+    pub weapons: HashMap<Weapon, WeaponStats>,
+    pub grenade: GrenadeStats,
+    // in degrees per second
+    pub turn_speed: f64,
+    // the width of the cone that units can see and shoot in, in degrees
+    pub field_of_view: f64,
+    // furthest that a walking unit can be seen from
+    pub sight_range: f64,
+    // modes that are left out get their default stats
+    pub move_modes: HashMap<MoveMode, MoveModeStats>,
+    // how close to the line of suppressing fire a unit has to be to get hit
+    pub suppress_width: f64,
+    // scales how quickly suppressing fire does damage
+    pub suppress_accuracy: f64,
+    // units are circles that other units can't walk through
+    pub unit_radius: f64,
+    // whether units get in the way of each other's line of sight
+    pub units_block_sight: bool,
+    // seconds it takes to open or close a door
+    pub door_time: f64,
+    // how close a unit has to be to a door to open or close it
+    pub door_reach: f64,
+    pub door_thickness: f64,
+    // fraction of their usual speed that units can walk at over each kind of
+    // ground, kinds that are left out get their default speed
+    pub terrain: HashMap<path::Material, f64>,
+}
+
+// fraction of the shotgun's kill time that it takes at point blank range
+const SHOTGUN_POINT_BLANK: f64 = 0.25;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrenadeStats {
+    // seconds spent winding up before the grenade leaves the thrower's hand
+    pub throw_time: f64,
+    // furthest the grenade can be thrown
+    pub range: f64,
+    // distance per second while in flight
+    pub speed: f64,
+    // seconds after leaving the thrower's hand that the grenade explodes
+    pub fuse: f64,
+    pub blast_radius: f64,
+    // health taken from each unit caught in the blast
+    pub damage: f64,
+}
+
+impl Default for GrenadeStats {
+    fn default() -> Self {
+        GrenadeStats {
+            throw_time: 0.5,
+            range: 15.0,
+            speed: 10.0,
+            fuse: 2.5,
+            blast_radius: 4.0,
+            damage: FULL_HEALTH,
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        let mut rules = Rules {
+            tick_length: 0.1,
+            weapons: HashMap::new(),
+            grenade: Default::default(),
+            turn_speed: 180.0,
+            field_of_view: 120.0,
+            sight_range: 200.0,
+            move_modes: HashMap::new(),
+            suppress_width: 1.0,
+            suppress_accuracy: 0.5,
+            unit_radius: 0.5,
+            units_block_sight: false,
+            door_time: 1.0,
+            door_reach: 1.5,
+            door_thickness: 1.0,
+            terrain: HashMap::new(),
+        };
+        rules.fill_weapons();
+        rules.fill_move_modes();
+        rules.fill_terrain();
+        rules
+    }
+}
+
+impl Rules {
+    // rounds up to a whole number of ticks, ignoring rounding error so that
+    // e.g. 1.1 seconds is 11 ticks rather than 12
+    pub fn ticks(self: &Self, secs: f64) -> Ticks {
+        (secs / self.tick_length - 1e-9).ceil() as Ticks
+    }
+
+    // for things that have to take some time, even if it rounds down to none
+    pub fn min_ticks(self: &Self, secs: f64) -> Ticks {
+        self.ticks(secs).max(1)
+    }
+
+    // values that get divided by or used as durations, which would make times
+    // infinite or negative, and have to be checked after filling in the
+    // defaults
+    pub fn check(self: &Self) -> Result<(), String> {
+        let positive = |name: String, value: f64| if value > 0.0 {
+            Ok(())
+        } else {
+            Err(format!("{} is {}, but it has to be more than 0", name, value))
+        };
+        let not_negative = |name: String, value: f64| if value >= 0.0 {
+            Ok(())
+        } else {
+            Err(format!("{} is {}, but it can't be less than 0", name, value))
+        };
+        positive("tick_length".to_string(), self.tick_length)?;
+        positive("turn_speed".to_string(), self.turn_speed)?;
+        for &weapon in &WEAPONS {
+            let stats = self.weapon(weapon);
+            let name = |field| format!("weapons[{:?}].{}", weapon, field);
+            positive(name("kill_time"), stats.kill_time)?;
+            positive(name("range"), stats.range)?;
+            not_negative(name("swap_time"), stats.swap_time)?;
+            // weapons without magazines never fire rounds or reload
+            if stats.mag_size > 0 {
+                positive(name("fire_rate"), stats.fire_rate)?;
+                positive(name("reload_time"), stats.reload_time)?;
+            }
+        }
+        not_negative("door_time".to_string(), self.door_time)?;
+        not_negative("grenade.fuse".to_string(), self.grenade.fuse)?;
+        positive("grenade.speed".to_string(), self.grenade.speed)?;
+        let throw_time = self.grenade.throw_time;
+        not_negative("grenade.throw_time".to_string(), throw_time)?;
+        for &mode in &MOVE_MODES {
+            let stats = self.move_mode(mode);
+            let name = format!("move_modes[{:?}]", mode);
+            positive(format!("{}.speed", name), stats.speed)?;
+            positive(format!("{}.accuracy", name), stats.accuracy)?;
+        }
+        // any material can be given a speed, not just the usual terrain
+        let mut terrain: Vec<_> = self.terrain
+            .iter()
+            .map(|(material, &speed)| (format!("{:?}", material), speed))
+            .collect();
+        terrain.sort_by(|a, b| a.0.cmp(&b.0));
+        for (material, speed) in terrain {
+            positive(format!("terrain[{}]", material), speed)?;
+        }
+        Ok(())
+    }
+
+    pub fn secs(self: &Self, ticks: Ticks) -> f64 {
+        ticks as f64 * self.tick_length
+    }
+
+    pub fn fill_weapons(self: &mut Self) {
+        for &weapon in &WEAPONS {
+            self.weapons
+                .entry(weapon)
+                .or_insert(weapon.default_stats());
+        }
+    }
+
+    pub fn weapon(self: &Self, weapon: Weapon) -> &WeaponStats {
+        &self.weapons[&weapon]
+    }
+
+    pub fn fill_move_modes(self: &mut Self) {
+        for &mode in &MOVE_MODES {
+            self.move_modes
+                .entry(mode)
+                .or_insert(mode.default_stats());
+        }
+    }
+
+    pub fn move_mode(self: &Self, mode: MoveMode) -> &MoveModeStats {
+        &self.move_modes[&mode]
+    }
+
+    pub fn fill_terrain(self: &mut Self) {
+        for &material in &path::TERRAIN {
+            self.terrain
+                .entry(material)
+                .or_insert(material.default_speed());
+        }
+    }
+
+    // fraction of the usual speed that a unit can walk at in a position,
+    // which is the slowest of the ground there
+    pub fn terrain_speed(self: &Self, map: &path::Map, pos: Vec2) -> f64 {
+        path::materials_at(map, pos)
+            .iter()
+            .map(|material| self.terrain.get(material).cloned().unwrap_or(1.0))
+            .fold(1.0, f64::min)
+    }
+
+    // how far along the line from p1 to p2 the walking speed changes, as
+    // fractions of the line, in order
+    pub fn terrain_changes(
+        self: &Self,
+        map: &path::Map,
+        p1: Vec2,
+        p2: Vec2,
+    ) -> Vec<f64> {
+        let at = |t: f64| {
+            let pos = vec2_add(p1, vec2_scale(vec2_sub(p2, p1), t));
+            self.terrain_speed(map, pos)
+        };
+        path::zone_crossings(map, p1, p2)
+            .into_iter()
+            .filter(|&t| at(t - 1e-6) != at(t + 1e-6))
+            .collect()
+    }
+
+    // the slowest that the ground gets anywhere on the line from p1 to p2
+    pub fn slowest_terrain(
+        self: &Self,
+        map: &path::Map,
+        p1: Vec2,
+        p2: Vec2,
+    ) -> f64 {
+        let mut bounds = self.terrain_changes(map, p1, p2);
+        bounds.insert(0, 0.0);
+        bounds.push(1.0);
+        (1..bounds.len())
+            .map(|i| {
+                let mid = (bounds[i - 1] + bounds[i]) / 2.0;
+                let pos = vec2_add(p1, vec2_scale(vec2_sub(p2, p1), mid));
+                self.terrain_speed(map, pos)
+            })
+            .fold(1.0, f64::min)
+    }
+
+    // furthest that a unit can be seen from, ignoring walls and facing
+    pub fn visible_range(self: &Self, unit: &UnitState) -> f64 {
+        self.sight_range * self.move_mode(unit.mode).visibility
+    }
+
+    // how long a shooter has to keep firing, target_loc is where the target
+    // was when the shooter started
+    pub fn kill_time(self: &Self, shooter: &UnitState) -> Ticks {
+        let stats = self.weapon(shooter.weapon);
+        let secs = match shooter.weapon {
+            Weapon::Gun | Weapon::Sniper | Weapon::Melee => stats.kill_time,
+            Weapon::Shotgun => {
+                // spread means close targets take more pellets
+                let disp = vec2_sub(shooter.target_loc, shooter.pos);
+                let dist = vecmath::vec2_len(disp) / stats.range;
+                stats.kill_time * dist.max(SHOTGUN_POINT_BLANK)
+            },
+        };
+        // damage is spread over these ticks, so there has to be at least one
+        self.min_ticks(secs / self.move_mode(shooter.mode).accuracy)
+    }
+
+    // health taken from the target per tick
+    pub fn damage_rate(self: &Self, shooter: &UnitState) -> f64 {
+        let rate = FULL_HEALTH / self.kill_time(shooter) as f64;
+        if shooter.action == Action::Suppress {
+            rate * self.suppress_accuracy
+        } else {
+            rate
+        }
+    }
+
+    // converts a speed in distance per second to distance per tick
+    pub fn speed(self: &Self, speed: f64) -> f64 {
+        speed * self.tick_length
+    }
+
+    // how long it takes to turn through an angle given in radians
+    pub fn turn_time(self: &Self, turn: f64) -> Ticks {
+        let turn_speed = self.turn_speed.to_radians();
+        self.min_ticks(turn.abs() / turn_speed)
+    }
+
+    pub fn max_spin(self: &Self) -> f64 {
+        self.speed(self.turn_speed.to_radians())
+    }
+
+    // whether a position falls in the unit's cone of vision, ignoring walls
+    pub fn in_view(self: &Self, unit: &UnitState, pos: Vec2) -> bool {
+        let disp = vec2_sub(pos, unit.pos);
+        if disp == [0.0, 0.0] {
+            return true;
+        }
+        let turn = angle_between(unit.facing, direction(disp));
+        turn.abs() <= self.field_of_view.to_radians() / 2.0
+    }
+
+    // how long it takes to get a weapon ready, always at least one tick
+    pub fn swap_time(self: &Self, weapon: Weapon) -> Ticks {
+        self.min_ticks(self.weapon(weapon).swap_time)
+    }
+
+    pub fn uses_ammo(self: &Self, weapon: Weapon) -> bool {
+        self.weapon(weapon).mag_size > 0
+    }
+
+    // rounds fired over a number of ticks, where a round that is only partly
+    // fired doesn't count
+    pub fn rounds_fired(self: &Self, weapon: Weapon, ticks: Ticks) -> u32 {
+        let rounds = self.secs(ticks) * self.weapon(weapon).fire_rate;
+        (rounds + 1e-9).floor() as u32
+    }
+
+    // how long a firing unit can keep going before its magazine is empty
+    pub fn empty_time(self: &Self, unit: &UnitState) -> Option<Ticks> {
+        if !unit.is_firing() || !self.uses_ammo(unit.weapon) {
+            return None;
+        }
+        let mag = unit.slot(unit.weapon).map_or(0, |slot| slot.mag);
+        let secs = mag as f64 / self.weapon(unit.weapon).fire_rate;
+        Some(self.min_ticks(secs))
+    }
+
+    pub fn reload_time(self: &Self, weapon: Weapon) -> Ticks {
+        self.min_ticks(self.weapon(weapon).reload_time)
+    }
+
+    // the unit's ammunition at a later time, assuming it keeps doing what it
+    // is doing until then, a reload only counts once it is finished
+    pub fn ammo_at(self: &Self, unit: &UnitState, time: Time) -> Loadout {
+        let mut loadout = unit.loadout;
+        let ticks = time - unit.time;
+        let stats = self.weapon(unit.weapon);
+        for slot in loadout.iter_mut().filter_map(|slot| slot.as_mut()) {
+            if slot.weapon != unit.weapon || stats.mag_size == 0 {
+                continue;
+            }
+            if unit.is_firing() {
+                let spent = self.rounds_fired(unit.weapon, ticks);
+                slot.mag -= spent.min(slot.mag);
+            } else if unit.action == Action::Reload
+                && ticks >= self.reload_time(unit.weapon)
+            {
+                let space = stats.mag_size.saturating_sub(slot.mag);
+                let rounds = space.min(slot.reserve);
+                slot.mag += rounds;
+                slot.reserve -= rounds;
+            }
+        }
+        loadout
+    }
+
+    pub fn door_time(self: &Self) -> Ticks {
+        self.min_ticks(self.door_time)
+    }
+
+    // always at least one tick, so that the throw lands after it started
+    pub fn throw_time(self: &Self) -> Ticks {
+        self.min_ticks(self.grenade.throw_time)
+    }
+
+    // how long a grenade takes to land, always at least one tick
+    pub fn flight_time(self: &Self, from: Vec2, to: Vec2) -> Ticks {
+        let dist = vecmath::vec2_len(vec2_sub(to, from));
+        self.min_ticks(dist / self.grenade.speed)
+    }
+
+    // the grenade that leaves the thrower's hand at the end of a throw
+    pub fn grenade_state(
+        self: &Self,
+        id: EID,
+        thrower: UnitState,
+    ) -> UnitState {
+        let flight = self.flight_time(thrower.pos, thrower.target_loc);
+        let disp = vec2_sub(thrower.target_loc, thrower.pos);
+        let detonation = thrower.time + self.ticks(self.grenade.fuse);
+        UnitState {
+            team: thrower.team,
+            pos: thrower.pos,
+            vel: vec2_scale(disp, 1.0 / flight as f64),
+            facing: direction(disp),
+            spin: 0.0,
+            mode: MoveMode::Walk,
+            weapon: thrower.weapon,
+            loadout: [None; LOADOUT_SIZE],
+            action: Action::Grenade(detonation),
+            target_loc: thrower.target_loc,
+            target_id: NULL_ID,
+            health: FULL_HEALTH,
+
+            time: thrower.time,
+            id,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Timeline {
     pub snapshots: BTreeMap<Time, Snapshot>,
@@ -40,7 +745,7 @@ impl Timeline {
     }
     pub fn insert(self: &mut Self, state: UnitState) -> Option<UnitState> {
         self.snapshots
-            .entry(Time(state.time))
+            .entry(state.time)
             .or_insert(Snapshot::with_time(state.time))
             .insert(state)
     }
@@ -65,15 +770,15 @@ impl iter::FromIterator<UnitState> for Timeline {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Snapshot {
-    pub time: f64,
+    pub time: Time,
     pub states: HashMap<EID, UnitState>,
 }
 
 impl Snapshot {
     pub fn new() -> Self {
-        Snapshot::with_time(0.0)
+        Snapshot::with_time(Time(0))
     }
-    pub fn with_time(time: f64) -> Self {
+    pub fn with_time(time: Time) -> Self {
         Snapshot {
             time,
             states: HashMap::new(),
@@ -84,14 +789,22 @@ impl Snapshot {
             .insert(state.id, state)
     }
 
+    // ids are never reused, so that the server and clients agree on the ids
+    // of new entities without having to communicate
+    pub fn next_id(self: &Self) -> EID {
+        self.states
+            .keys()
+            .max()
+            .map(|&id| id + 1)
+            .unwrap_or(0)
+    }
+
     pub fn update_precise(
         self: &mut Snapshot,
         timeline: &Timeline,
-        new_time: f64,
+        new_time: Time,
     ) {
-        let t1 = Time(self.time);
-        let t2 = Time(new_time);
-        let range = ::std::ops::RangeInclusive::new(t1, t2);
+        let range = ::std::ops::RangeInclusive::new(self.time, new_time);
         for (_, units) in timeline.snapshots.range(range) {
             self.copy_units(units);
         }
@@ -101,7 +814,7 @@ impl Snapshot {
     pub fn update(
         self: &mut Snapshot,
         timeline: &Timeline,
-        new_time: f64,
+        new_time: Time,
     ) {
         self.update_precise(timeline, new_time);
         for (_, unit) in &mut self.states {
@@ -136,3 +849,31 @@ impl iter::FromIterator<UnitState> for Snapshot {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_weapon_stats() {
+        let text = "(weapons: { Gun: (mag_size: 8), Sniper: (range: 50.0) })";
+        let mut rules: Rules = ::ron::de::from_str(text).unwrap();
+        rules.fill_weapons();
+        let gun = Weapon::Gun.default_stats();
+        assert_eq!(rules.weapon(Weapon::Gun).mag_size, 8);
+        assert_eq!(rules.weapon(Weapon::Gun).kill_time, gun.kill_time);
+        assert_eq!(rules.weapon(Weapon::Sniper).range, 50.0);
+        assert_eq!(
+            rules.weapon(Weapon::Shotgun),
+            &Weapon::Shotgun.default_stats(),
+        );
+    }
+    // the server sends the rules to its clients with bincode
+    #[test]
+    fn rules_over_the_wire() {
+        let rules = Rules::default();
+        let bytes = ::bincode::serialize(&rules).unwrap();
+        let sent: Rules = ::bincode::deserialize(&bytes).unwrap();
+        assert_eq!(sent, rules);
+    }
+}
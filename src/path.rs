@@ -1,11 +1,126 @@
+use std::collections::HashSet;
 use vecmath;
-use piston_window;
 
 use prelude::*;
 
 pub type Trig = [Vec2; 3];
 
-pub type Map = Vec<Trig>;
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Material {
+    Wall,
+    // can be seen and shot over, and grenades can be thrown over it
+    LowCover,
+    // can be seen and shot through
+    Glass,
+    // smoke, foliage and the like, which can be walked through
+    Smoke,
+    // ground that slows units down, see model::Rules::terrain
+    Mud,
+    Water,
+    Stairs,
+}
+
+pub const TERRAIN: [Material; 3] = [
+    Material::Mud,
+    Material::Water,
+    Material::Stairs,
+];
+
+impl Material {
+    pub fn blocks_movement(self: Self) -> bool {
+        match self {
+            Material::Wall | Material::LowCover | Material::Glass => true,
+            _ => false,
+        }
+    }
+
+    pub fn blocks_sight(self: Self) -> bool {
+        match self {
+            Material::Wall | Material::Smoke => true,
+            _ => false,
+        }
+    }
+
+    pub fn blocks_grenades(self: Self) -> bool {
+        match self {
+            Material::Wall | Material::Glass => true,
+            _ => false,
+        }
+    }
+
+    // fraction of their usual speed that units can walk at
+    pub fn default_speed(self: Self) -> f64 {
+        match self {
+            Material::Mud => 0.5,
+            Material::Water => 0.3,
+            Material::Stairs => 0.7,
+            _ => 1.0,
+        }
+    }
+}
+
+// each triangle belongs to the wall entity that can destroy it, or NULL_ID if
+// it can't be destroyed
+pub type Map = Vec<(Trig, Material, EID)>;
+
+// takes a destroyed wall out of the map
+pub fn destroy(map: &mut Map, wall: EID) {
+    map.retain(|&(_, _, owner)| owner != wall);
+}
+
+// opposite corners of the arena, with the smaller coordinates first
+pub type Bounds = [Vec2; 2];
+
+// how far the walls around the arena reach out from its edges, which just
+// needs to be more than anything can move in a tick
+const BOUNDS_THICKNESS: f64 = 10.0;
+
+pub fn in_bounds(bounds: Bounds, pos: Vec2) -> bool {
+    let [lo, hi] = bounds;
+    lo[0] <= pos[0] && pos[0] <= hi[0] && lo[1] <= pos[1] && pos[1] <= hi[1]
+}
+
+// walls that surround the arena, so that nothing can leave it
+pub fn bounding_walls(bounds: Bounds) -> Map {
+    let [[x1, y1], [x2, y2]] = bounds;
+    let t = BOUNDS_THICKNESS;
+    let rects = [
+        [[x1 - t, y1 - t], [x2 + t, y1]],
+        [[x1 - t, y2], [x2 + t, y2 + t]],
+        [[x1 - t, y1], [x1, y2]],
+        [[x2, y1], [x2 + t, y2]],
+    ];
+    let mut result = Map::new();
+    for &[[x1, y1], [x2, y2]] in &rects {
+        let trigs = [
+            [[x1, y1], [x2, y1], [x2, y2]],
+            [[x1, y1], [x2, y2], [x1, y2]],
+        ];
+        for &trig in &trigs {
+            result.push((trig, Material::Wall, NULL_ID));
+        }
+    }
+    result
+}
+
+// whether a position is within a distance of any part of a wall
+pub fn near_wall(map: &Map, wall: EID, pos: Vec2, radius: f64) -> bool {
+    for &(trig, _, owner) in map {
+        if owner != wall {
+            continue;
+        }
+        if inside_triangle(trig, pos) {
+            return true;
+        }
+        for i in 0..3 {
+            let j = (i + 1) % 3;
+            if in_capsule(trig[i], trig[j], radius, pos) {
+                return true;
+            }
+        }
+    }
+    false
+}
 
 #[derive(Copy, Clone)]
 struct Line {
@@ -26,18 +141,165 @@ impl Line {
     }
 }
 
-pub fn unit_is_in_wall(map: &Map, unit: Vec2) -> bool {
-    for trig in map {
-        if piston_window::math::inside_triangle(*trig, unit) {
+// which side of each edge of the triangle the point is on, with the sign
+// flipping as the point crosses the edge, and zero on the edge itself
+fn edge_sides(trig: Trig, pos: Vec2) -> [f64; 3] {
+    let side = |p1: Vec2, p2: Vec2| {
+        let edge = vec2_sub(p2, p1);
+        let disp = vec2_sub(pos, p1);
+        edge[0] * disp[1] - edge[1] * disp[0]
+    };
+    [
+        side(trig[0], trig[1]),
+        side(trig[1], trig[2]),
+        side(trig[2], trig[0]),
+    ]
+}
+
+// same test as piston's graphics::math::inside_triangle, so that the server
+// doesn't need the graphics stack
+fn inside_triangle(trig: Trig, pos: Vec2) -> bool {
+    let [s1, s2, s3] = edge_sides(trig, pos);
+    (s1 > 0.0 && s2 > 0.0 && s3 > 0.0) || (s1 < 0.0 && s2 < 0.0 && s3 < 0.0)
+}
+
+// also counts the edges, so that points on the seams between the triangles of
+// a polygon are still inside it
+fn touches_triangle(trig: Trig, pos: Vec2) -> bool {
+    let [s1, s2, s3] = edge_sides(trig, pos);
+    (s1 >= 0.0 && s2 >= 0.0 && s3 >= 0.0)
+        || (s1 <= 0.0 && s2 <= 0.0 && s3 <= 0.0)
+}
+
+fn is_inside(map: &Map, pos: Vec2, blocks: fn(Material) -> bool) -> bool {
+    for &(trig, material, _) in map {
+        if blocks(material) && inside_triangle(trig, pos) {
             return true;
         }
     }
     false
 }
 
+// the ground that a unit is standing on, which can be more than one thing
+// where polygons overlap
+pub fn materials_at(map: &Map, pos: Vec2) -> Vec<Material> {
+    map.iter()
+        .filter(|&&(trig, material, _)| {
+            !material.blocks_movement() && touches_triangle(trig, pos)
+        })
+        .map(|&(_, material, _)| material)
+        .collect()
+}
+
+// how far along the line from p1 to p2 it crosses into or out of ground that
+// can be walked on, as fractions of the line, in order
+pub fn zone_crossings(map: &Map, p1: Vec2, p2: Vec2) -> Vec<f64> {
+    let test_line = Line::from_points(p1, p2);
+    let mut result = Vec::new();
+    for &(trig, material, _) in map {
+        if material.blocks_movement() {
+            continue;
+        }
+        for i in 0..3 {
+            let j = (i + 1) % 3;
+            let edge = Line::from_points(trig[i], trig[j]);
+            if let Some(vals) = line_intersect_args(test_line, edge) {
+                let t = vals[0];
+                let on_edge = 0.0 <= vals[1] && vals[1] <= 1.0;
+                if on_edge && 1e-9 < t && t < 1.0 - 1e-9 {
+                    result.push(t);
+                }
+            }
+        }
+    }
+    result.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    result.dedup();
+    result
+}
+
+pub fn unit_is_in_wall(map: &Map, unit: Vec2) -> bool {
+    is_inside(map, unit, Material::blocks_movement)
+}
+
+pub fn grenade_is_in_wall(map: &Map, grenade: Vec2) -> bool {
+    is_inside(map, grenade, Material::blocks_grenades)
+}
+
 pub fn unit_can_see_pos(map: &Map, unit: Vec2, pos: Vec2) -> bool {
-    let test_line = Line::from_points(unit, pos);
-    for trig in map {
+    is_clear(map, unit, pos, Material::blocks_sight, NULL_ID)
+}
+
+// for shooting at a wall, which shouldn't get in the way of itself
+pub fn unit_can_see_wall(map: &Map, unit: Vec2, wall: EID, pos: Vec2)
+    -> bool
+{
+    is_clear(map, unit, pos, Material::blocks_sight, wall)
+}
+
+// explosions are stopped by anything solid
+pub fn blast_reaches_pos(map: &Map, blast: Vec2, pos: Vec2) -> bool {
+    is_clear(map, blast, pos, Material::blocks_movement, NULL_ID)
+}
+
+// whether a unit could walk from one position to each of the others, by
+// walking between the points of a grid with the given spacing
+pub fn reachable(
+    map: &Map,
+    bounds: Bounds,
+    from: Vec2,
+    to: &[Vec2],
+    step: f64,
+) -> bool {
+    let point = |(i, j): (i64, i64)| {
+        vec2_add(from, [i as f64 * step, j as f64 * step])
+    };
+    let can_walk = |p1: Vec2, p2: Vec2| {
+        in_bounds(bounds, p2)
+            && !unit_is_in_wall(map, p2)
+            && is_clear(map, p1, p2, Material::blocks_movement, NULL_ID)
+    };
+    let mut visited = HashSet::new();
+    let mut queue = vec![(0, 0)];
+    visited.insert((0, 0));
+    while let Some((i, j)) = queue.pop() {
+        for &(di, dj) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let next = (i + di, j + dj);
+            if visited.contains(&next) {
+                continue;
+            }
+            if can_walk(point((i, j)), point(next)) {
+                visited.insert(next);
+                queue.push(next);
+            }
+        }
+    }
+    to.iter().all(|&pos| {
+        let i = (pos[0] - from[0]) / step;
+        let j = (pos[1] - from[1]) / step;
+        let corners = [
+            (i.floor() as i64, j.floor() as i64),
+            (i.floor() as i64, j.ceil() as i64),
+            (i.ceil() as i64, j.floor() as i64),
+            (i.ceil() as i64, j.ceil() as i64),
+        ];
+        corners.iter().any(|&corner| {
+            visited.contains(&corner) && can_walk(point(corner), pos)
+        })
+    })
+}
+
+fn is_clear(
+    map: &Map,
+    p1: Vec2,
+    p2: Vec2,
+    blocks: fn(Material) -> bool,
+    ignore: EID,
+) -> bool {
+    let test_line = Line::from_points(p1, p2);
+    for &(trig, material, owner) in map {
+        if !blocks(material) || (owner == ignore && ignore != NULL_ID) {
+            continue;
+        }
         for i in 0..3 {
             let j = (i + 1) % 3;
             let map_line = Line::from_points(trig[i], trig[j]);
@@ -78,6 +340,94 @@ fn intervals_intersect(line1: Line, line2: Line) -> bool {
     }
 }
 
+// the times t for which c0 + c1 t lies between lo and hi
+fn linear_times(c0: f64, c1: f64, lo: f64, hi: f64) -> Option<(f64, f64)> {
+    use std::f64::INFINITY;
+    if c1 == 0.0 {
+        if lo <= c0 && c0 <= hi {
+            Some((-INFINITY, INFINITY))
+        } else {
+            None
+        }
+    } else {
+        let t1 = (lo - c0) / c1;
+        let t2 = (hi - c0) / c1;
+        Some((t1.min(t2), t1.max(t2)))
+    }
+}
+
+// the times t for which pos + vel t is within radius of centre
+fn circle_times(pos: Vec2, vel: Vec2, centre: Vec2, radius: f64)
+    -> Option<(f64, f64)>
+{
+    use std::f64::INFINITY;
+    let disp = vec2_sub(pos, centre);
+    let a = vecmath::vec2_dot(vel, vel);
+    let b = 2.0 * vecmath::vec2_dot(disp, vel);
+    let c = vecmath::vec2_dot(disp, disp) - radius * radius;
+    if a == 0.0 {
+        return if c <= 0.0 {
+            Some((-INFINITY, INFINITY))
+        } else {
+            None
+        };
+    }
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return None;
+    }
+    let root = disc.sqrt();
+    Some(((-b - root) / (2.0 * a), (-b + root) / (2.0 * a)))
+}
+
+// the times t for which pos + vel t is within radius of the line segment
+// from p1 to p2, which is always one interval since the region is convex
+pub fn capsule_times(
+    p1: Vec2,
+    p2: Vec2,
+    radius: f64,
+    pos: Vec2,
+    vel: Vec2,
+) -> Option<(f64, f64)> {
+    let mut parts = vec![
+        circle_times(pos, vel, p1, radius),
+        circle_times(pos, vel, p2, radius),
+    ];
+    let len = vecmath::vec2_len(vec2_sub(p2, p1));
+    if len > 0.0 {
+        let along = vec2_scale(vec2_sub(p2, p1), 1.0 / len);
+        let across = [-along[1], along[0]];
+        let disp = vec2_sub(pos, p1);
+        let dot = vecmath::vec2_dot;
+        let x = linear_times(dot(disp, along), dot(vel, along), 0.0, len);
+        let y = linear_times(
+            dot(disp, across),
+            dot(vel, across),
+            -radius,
+            radius,
+        );
+        if let (Some(x), Some(y)) = (x, y) {
+            if x.0.max(y.0) <= x.1.min(y.1) {
+                parts.push(Some((x.0.max(y.0), x.1.min(y.1))));
+            }
+        }
+    }
+    parts.into_iter()
+        .filter_map(|part| part)
+        .fold(None, |acc, (start, end)| match acc {
+            None => Some((start, end)),
+            Some((s, e)) => Some((s.min(start), e.max(end))),
+        })
+}
+
+pub fn lines_cross(p1: Vec2, p2: Vec2, q1: Vec2, q2: Vec2) -> bool {
+    intervals_intersect(Line::from_points(p1, p2), Line::from_points(q1, q2))
+}
+
+pub fn in_capsule(p1: Vec2, p2: Vec2, radius: f64, pos: Vec2) -> bool {
+    capsule_times(p1, p2, radius, pos, [0.0, 0.0]).is_some()
+}
+
 #[derive(Clone)]
 pub struct NavMesh {
     points: Vec<Vec2>,
@@ -163,8 +513,11 @@ impl NavMesh {
 
     fn generate_points(self: &mut Self, map: &Map, radius: f64) {
         self.points = Vec::with_capacity(map.len() * 6);
-        for trig in map {
-            for &p in &trig_nav(trig, radius) {
+        for &(trig, material, _) in map {
+            if !material.blocks_movement() {
+                continue;
+            }
+            for &p in &trig_nav(&trig, radius) {
                 self.points.push(p);
             }
         }
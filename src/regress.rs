@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use prelude::*;
+
+use model;
+use save;
+use script;
+use server::Server;
+use client::{Client, Plan};
+
+// a regression case names a scenario, and gives each team a plan written in
+// the plan language from `script`, e.g.
+// (
+//     scenario: "../conflict",
+//     plans: {
+//         0: "unit 0: nav 20,33; shoot 1",
+//         1: "unit 1: wait 2",
+//     },
+// )
+// the scenario path is relative to the case file, and the confirmed timeline
+// is compared against a golden file next to the case, with `.golden` appended
+#[derive(Serialize, Deserialize)]
+pub struct Case {
+    pub scenario: String,
+    pub plans: HashMap<TID, String>,
+}
+
+pub enum Outcome {
+    Pass,
+    Blessed,
+    Fail(String),
+}
+
+// a runaway simulation usually means a unit is stuck retrying a command
+const MAX_STEPS: usize = 10_000;
+
+pub fn golden_path(case: &Path) -> PathBuf {
+    let mut name = case.as_os_str().to_owned();
+    name.push(".golden");
+    PathBuf::from(name)
+}
+
+pub fn run_case(case: &Path, bless: bool) -> Result<Outcome, String> {
+    let text = fs::read_to_string(case)
+        .map_err(|err| format!("Couldn't read {}: {}", case.display(), err))?;
+    let Case { scenario, plans } = ::ron::de::from_str(&text)
+        .map_err(|err| format!("Couldn't parse {}: {}", case.display(), err))?;
+
+    let dir = case.parent().unwrap_or(Path::new("."));
+    let scenario = dir.join(scenario).to_string_lossy().into_owned();
+    let (teams, init, map, rules, _, _) = save::read_scenario(&scenario)
+        .map_err(|err| err.to_string())?;
+
+    let mut team_plans = HashMap::new();
+    for (team, text) in plans {
+        let plan = script::parse(&text)
+            .map_err(|err| format!("Team {} plan, {}", team, err))?;
+        team_plans.insert(team, plan);
+    }
+
+    let timeline = simulate(&teams, init, map, rules, team_plans)?;
+    let actual = print_timeline(&timeline);
+
+    let golden = golden_path(case);
+    let name = golden.display();
+    if bless {
+        fs::write(&golden, &actual)
+            .map_err(|err| format!("Couldn't write {}: {}", name, err))?;
+        return Ok(Outcome::Blessed);
+    }
+    let expected = fs::read_to_string(&golden)
+        .map_err(|err| format!("Couldn't read {}: {}", name, err))?;
+    if expected == actual {
+        Ok(Outcome::Pass)
+    } else {
+        Ok(Outcome::Fail(diff(&expected, &actual)))
+    }
+}
+
+// plays the game out the same way server_app and client_app would, with one
+// client per team, until every team runs out of commands
+pub fn simulate(
+    teams: &HashMap<EID, TID>,
+    init: model::Snapshot,
+    map: ::path::Map,
+    rules: model::Rules,
+    plans: HashMap<TID, Plan>,
+) -> Result<model::Timeline, String> {
+    let mut server = Server::new(init.clone(), map.clone(), rules.clone());
+    let mut clients = HashMap::new();
+    for &team in teams.values() {
+        let client = Client::new(init.clone(), map.clone(), rules.clone());
+        clients.insert(team, client);
+    }
+    for (team, plan) in plans {
+        let client = clients.get_mut(&team)
+            .ok_or(format!("Scenario has no team {}", team))?;
+        for (id, commands) in plan {
+            if teams.get(&id) != Some(&team) {
+                return Err(format!("Team {} doesn't own unit {}", team, id));
+            }
+            client.check_commands(&commands)
+                .map_err(|msg| format!("Plan for unit {}: {}", id, msg))?;
+            client.plans.insert(id, commands);
+        }
+    }
+
+    let mut timeline = model::Timeline::new();
+    for _ in 0..MAX_STEPS {
+        let mut expected = HashMap::new();
+        let mut moves = Vec::new();
+        for (&team, client) in &clients {
+            let next = client.next_moves();
+            for (id, &unit) in &next {
+                if teams[id] == team {
+                    moves.push(unit);
+                }
+            }
+            expected.insert(team, next);
+        }
+
+        let result = server.resolve(moves.into_iter())
+            .map_err(|id| format!("Invalid move submitted for unit {}", id))?;
+        if result.states.len() == 0 {
+            return Ok(timeline);
+        }
+        for (team, client) in &mut clients {
+            client.accept_outcome(&expected[team], &result);
+        }
+        timeline.snapshots.insert(result.time, result);
+    }
+    Err(format!("Simulation still running after {} steps", MAX_STEPS))
+}
+
+pub fn print_timeline(timeline: &model::Timeline) -> String {
+    let mut result = String::new();
+    for (_, snap) in &timeline.snapshots {
+        result.push_str(&format!("time {}\n", snap.time.0));
+        let mut ids: Vec<&EID> = snap.states.keys().collect();
+        ids.sort();
+        for id in ids {
+            let unit = snap.states[id];
+            let target = if unit.target_id == NULL_ID {
+                "-".to_string()
+            } else {
+                unit.target_id.to_string()
+            };
+            let ammo = match unit.slot(unit.weapon) {
+                Some(slot) if slot.mag > 0 || slot.reserve > 0 => {
+                    format!("{}/{}", slot.mag, slot.reserve)
+                },
+                _ => "-".to_string(),
+            };
+            result.push_str(&format!(
+                "    unit {}: pos {},{} vel {},{} {:?} facing {} \
+                 {:?} ammo {} {:?} target {} hp {}\n",
+                id,
+                unit.pos[0],
+                unit.pos[1],
+                unit.vel[0],
+                unit.vel[1],
+                unit.mode,
+                unit.facing.to_degrees(),
+                unit.weapon,
+                ammo,
+                unit.action,
+                target,
+                unit.health,
+            ));
+        }
+    }
+    result
+}
+
+// lines around a change that get printed in a diff
+const CONTEXT: usize = 2;
+
+// line diff based on the longest common subsequence, which is plenty for
+// timelines of a few hundred lines
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len()
+            && (i == old.len() || lcs[i][j + 1] > lcs[i + 1][j])
+        {
+            lines.push(('+', new[j]));
+            j += 1;
+        } else {
+            lines.push(('-', old[i]));
+            i += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|&k| lines[k].0 != ' ')
+        .collect();
+    let near_change = |k: usize| changed
+        .iter()
+        .any(|&c| c <= k + CONTEXT && k <= c + CONTEXT);
+
+    let mut result = String::new();
+    let mut skipped = false;
+    for k in 0..lines.len() {
+        if near_change(k) {
+            result.push_str(&format!("{} {}\n", lines[k].0, lines[k].1));
+            skipped = false;
+        } else if !skipped {
+            result.push_str("  ...\n");
+            skipped = true;
+        }
+    }
+    result
+}
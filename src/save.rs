@@ -1,70 +1,789 @@
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::fmt;
 
 use prelude::*;
 
 use model;
 use path;
 
-#[derive(Serialize, Deserialize)]
-struct Unit {
-    team: TID,
-    pos: (f64, f64),
-    weapon: model::Weapon,
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Unit {
+    pub team: TID,
+    pub pos: (f64, f64),
+    pub weapon: model::Weapon,
+    // weapons carried besides the one in the unit's hands
+    #[serde(default)]
+    pub loadout: Vec<model::Weapon>,
+    #[serde(default)]
+    pub name: Option<String>,
+    // in degrees, turning the same way as UnitState::facing and Command::Face
+    #[serde(default)]
+    pub facing: f64,
 }
-#[derive(Serialize, Deserialize)]
-struct Scenario {
-    units: Vec<Unit>,
-    map: Vec<Vec<Vec2>>,
+// polygons in `map` are walls, and polygons made of anything else, or that
+// can be destroyed, go in `polys`
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Poly {
+    #[serde(default = "wall")]
+    pub material: path::Material,
+    pub points: Vec<Vec2>,
+    // hit points, in units of a unit's full health, for polygons that can be
+    // destroyed
+    #[serde(default)]
+    pub hp: Option<f64>,
+}
+
+fn wall() -> path::Material {
+    path::Material::Wall
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Door {
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+    #[serde(default)]
+    pub open: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Team {
+    pub name: String,
+    // red, green and blue, from 0 to 1
+    pub colour: [f32; 3],
+}
+
+// everything about a scenario that is just for the players, and doesn't
+// affect the simulation
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScenarioInfo {
+    pub title: String,
+    pub author: String,
+    pub description: String,
+    // indexed by team id, teams that are left out get a default name and
+    // colour
+    pub teams: Vec<Team>,
+    pub players: Option<usize>,
+    pub unit_names: HashMap<EID, String>,
+}
+
+impl ScenarioInfo {
+    pub fn team(self: &Self, team: TID) -> Team {
+        let colours = [
+            [0.3, 0.6, 1.0],
+            [1.0, 0.3, 0.3],
+            [0.3, 1.0, 0.3],
+            [1.0, 1.0, 0.3],
+        ];
+        self.teams.get(team as usize).cloned().unwrap_or(Team {
+            name: format!("Team {}", team + 1),
+            colour: colours[team as usize % colours.len()],
+        })
+    }
+
+    pub fn unit_name(self: &Self, id: EID) -> String {
+        self.unit_names.get(&id)
+            .cloned()
+            .unwrap_or(format!("Unit {}", id))
+    }
+}
+
+// rules can be written into the scenario, or shared between scenarios by
+// putting them in a separate file, named relative to the scenario
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub teams: Vec<Team>,
+    // how many players the scenario is for, which has to match the number of
+    // teams when it is given
+    #[serde(default)]
+    pub players: Option<usize>,
+    pub units: Vec<Unit>,
+    pub map: Vec<Vec<Vec2>>,
+    #[serde(default)]
+    pub polys: Vec<Poly>,
+    #[serde(default)]
+    pub doors: Vec<Door>,
+    // scenarios without bounds go on forever
+    #[serde(default)]
+    pub bounds: Option<path::Bounds>,
+    // scenarios that are written out get the default rules, or those in
+    // rules_file
+    #[serde(default, skip_serializing)]
+    pub rules: model::Rules,
+    #[serde(default)]
+    pub rules_file: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ScenarioError {
+    pub path: String,
+    // line and column of the error, or of the value it is about
+    pub pos: Option<(usize, usize)>,
+    pub msg: String,
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some((line, col)) => {
+                write!(f, "{}:{}:{}: {}", self.path, line, col, self.msg)
+            },
+            None => write!(f, "{}: {}", self.path, self.msg),
+        }
+    }
+}
+
+impl ScenarioError {
+    fn new(path: &str, msg: String) -> Self {
+        ScenarioError {
+            path: path.to_string(),
+            pos: None,
+            msg,
+        }
+    }
+
+    // for errors found after parsing, which start with the path to the value
+    // they are about, under the given prefix
+    fn at(path: &str, text: &str, prefix: &str, msg: String) -> Self {
+        let value = msg.split(' ').next().unwrap_or("");
+        ScenarioError {
+            path: path.to_string(),
+            pos: find_path(text, &format!("{}{}", prefix, value)),
+            msg,
+        }
+    }
+}
+
+fn read_text(path: &str) -> Result<String, ScenarioError> {
+    ::std::fs::read_to_string(path)
+        .map_err(|err| ScenarioError::new(path, err.to_string()))
 }
 
-pub fn read_scenario(path: &String)
-    -> (HashMap<EID, TID>, model::Snapshot, path::Map)
+fn read_ron<T>(path: &str, text: &str) -> Result<T, ScenarioError>
+    where T: for<'de> ::serde::Deserialize<'de>
 {
-    let mut file = ::std::fs::File::open(path)
-        .expect("Couldn't open file");
-    let mut stuff = String::new();
-    use std::io::Read;
-    file.read_to_string(&mut stuff)
-        .expect("Couldn't read file");
+    ::ron::de::from_str(text).map_err(|err| match err {
+        ::ron::de::Error::Parser(code, pos) => ScenarioError {
+            path: path.to_string(),
+            pos: Some((pos.line, pos.col)),
+            msg: format!("{:?}", code),
+        },
+        err => ScenarioError {
+            path: path.to_string(),
+            pos: guess_pos(text, &err.to_string()),
+            msg: err.to_string(),
+        },
+    })
+}
+
+// serde's own errors, like unknown variants and fields, don't say where they
+// are, so point at the first place that the name they complain about appears
+fn guess_pos(text: &str, msg: &str) -> Option<(usize, usize)> {
+    let name = msg.split('`').nth(1)?;
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split("//").next().unwrap();
+        for (col, _) in line.match_indices(name) {
+            let before = line[..col].chars().next_back();
+            let after = line[col + name.len()..].chars().next();
+            if !before.into_iter().chain(after).any(is_ident) {
+                return Some((i + 1, col + 1));
+            }
+        }
+    }
+    None
+}
+
+// follows a path like units[3], weapons[Shotgun].range or grenade.fuse
+// through the text, and gives the line and column of the value it ends at
+fn find_path(text: &str, path: &str) -> Option<(usize, usize)> {
+    let mut start = skip_blank(text, 0);
+    for field in path.split('.') {
+        let mut keys = field.split('[');
+        let name = keys.next()?;
+        start = find_entry(text, start, name)?;
+        for key in keys {
+            start = find_entry(text, start, key.trim_end_matches(']'))?;
+        }
+    }
+    let line = text[..start].matches('\n').count() + 1;
+    let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+    Some((line, text[line_start..start].chars().count() + 1))
+}
+
+// finds the value of a field in a struct or a key in a map, or the nth value
+// in a list, given where that struct, map or list starts
+fn find_entry(text: &str, start: usize, key: &str) -> Option<usize> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    // structs can be written with their name in front, as in Some(..)
+    let open = start + text[start..].find(|c| !is_ident(c))?;
+    let entries = entries(text, skip_blank(text, open))?;
+    if let Ok(n) = key.parse::<usize>() {
+        return entries.get(n).cloned();
+    }
+    entries.into_iter().filter_map(|entry| {
+        let rest = &text[entry..];
+        let name = rest.trim_start_matches('"');
+        let len = name.find(|c| !is_ident(c)).unwrap_or(name.len());
+        if &name[..len] != key {
+            return None;
+        }
+        let after = entry + rest.len() - name.len() + len;
+        let colon = skip_blank(text, after + text[after..].find(':')? + 1);
+        Some(colon)
+    }).next()
+}
+
+// where each entry starts, between the bracket at open and its match
+fn entries(text: &str, open: usize) -> Option<Vec<usize>> {
+    match text[open..].chars().next() {
+        Some('(') | Some('[') | Some('{') => (),
+        _ => return None,
+    }
+    let mut result = vec![skip_blank(text, open + 1)];
+    let mut depth = 0;
+    let mut i = open + 1;
+    while i < text.len() {
+        let rest = &text[i..];
+        let c = rest.chars().next()?;
+        if rest.starts_with("//") || c.is_whitespace() {
+            i = skip_blank(text, i);
+            continue;
+        }
+        match c {
+            '"' => {
+                let mut escaped = false;
+                let end = rest[1..].char_indices().find(|&(_, c)| {
+                    let quote = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    quote
+                })?;
+                i += end.0 + 2;
+                continue;
+            },
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => {
+                // a trailing comma leaves an empty entry at the end
+                if result.last() == Some(&i) {
+                    result.pop();
+                }
+                return Some(result);
+            },
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => result.push(skip_blank(text, i + 1)),
+            _ => (),
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+fn skip_blank(text: &str, mut i: usize) -> usize {
+    loop {
+        let rest = &text[i..];
+        if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else {
+            match rest.chars().next() {
+                Some(c) if c.is_whitespace() => i += c.len_utf8(),
+                _ => return i,
+            }
+        }
+    }
+}
+
+pub fn read_scenario(path: &String) -> Result<(
+    HashMap<EID, TID>,
+    model::Snapshot,
+    path::Map,
+    model::Rules,
+    Option<path::Bounds>,
+    ScenarioInfo,
+), ScenarioError> {
+    let text = read_text(path)?;
+    let Scenario {
+        title,
+        author,
+        description,
+        teams: team_info,
+        players,
+        units,
+        map,
+        polys,
+        doors,
+        bounds,
+        mut rules,
+        rules_file,
+    } = read_ron(path, &text)?;
 
-    let Scenario { units, map } = ::ron::de::from_str(&stuff)
-        .expect("Failed to read file");
+    let mut rules_path = path.clone();
+    let mut rules_text = text.clone();
+    let mut prefix = "rules.";
+    if let Some(rules_file) = rules_file {
+        let dir = ::std::path::Path::new(path)
+            .parent()
+            .unwrap_or(::std::path::Path::new("."));
+        rules_path = dir.join(rules_file).to_string_lossy().into_owned();
+        rules_text = read_text(&rules_path)?;
+        rules = read_ron(&rules_path, &rules_text)?;
+        prefix = "";
+    }
+    rules.fill_weapons();
+    rules.fill_move_modes();
+    rules.fill_terrain();
+    rules.check().map_err(|msg| {
+        ScenarioError::at(&rules_path, &rules_text, prefix, msg)
+    })?;
+
+    let error = |msg| ScenarioError::at(path, &text, "", msg);
+    let (teams, mut init, unit_names) = read_units(units, &rules)
+        .map_err(error)?;
+    let info = ScenarioInfo {
+        title,
+        author,
+        description,
+        teams: team_info,
+        players,
+        unit_names,
+    };
+    check_teams(&teams, &info).map_err(error)?;
+    read_doors(&mut init, doors);
+    let mut map = read_map(map, polys, &mut init).map_err(error)?;
+    if let Some(bounds) = bounds {
+        map.extend(path::bounding_walls(bounds));
+    }
+    for id in 0..teams.len() as EID {
+        let pos = init.states[&id].pos;
+        let outside = bounds.map_or(false, |b| !path::in_bounds(b, pos));
+        if outside {
+            let msg = format!("units[{}] starts outside the arena", id);
+            return Err(error(msg));
+        }
+        if path::unit_is_in_wall(&map, pos) {
+            return Err(error(format!("units[{}] starts inside a wall", id)));
+        }
+    }
+
+    Ok((teams, init, map, rules, bounds, info))
+}
 
-    let (teams, init) = read_units(units);
-    let map = read_map(map);
+pub fn write_scenario(scenario: &Scenario) -> String {
+    ::ron::ser::to_string_pretty(scenario, Default::default())
+        .expect("Failed to write scenario")
+}
 
-    (teams, init, map)
+// teams are numbered from 0 without gaps, since players are given the lowest
+// numbered team that is still open
+fn check_teams(
+    teams: &HashMap<EID, TID>,
+    info: &ScenarioInfo,
+) -> Result<(), String> {
+    let unit_teams = teams;
+    let teams: BTreeSet<TID> = teams.values().cloned().collect();
+    for (expected, &team) in teams.iter().enumerate() {
+        if team != expected as TID {
+            let unit = unit_teams
+                .iter()
+                .filter(|&(_, &unit_team)| unit_team == team)
+                .map(|(&id, _)| id)
+                .min()
+                .unwrap();
+            return Err(format!(
+                "units[{}] is on team {}, but there are no units on team {}",
+                unit,
+                team,
+                expected,
+            ));
+        }
+    }
+    if info.teams.len() > teams.len() {
+        return Err(format!(
+            "teams[{}] is listed, but only {} teams have units",
+            teams.len(),
+            teams.len(),
+        ));
+    }
+    match info.players {
+        Some(players) if players != teams.len() => Err(format!(
+            "the scenario is for {} players, but there are {} teams",
+            players,
+            teams.len(),
+        )),
+        _ => Ok(()),
+    }
 }
 
-fn read_units(units: Vec<Unit>) -> (HashMap<EID, TID>, model::Snapshot) {
+fn read_units(units: Vec<Unit>, rules: &model::Rules) -> Result<(
+    HashMap<EID, TID>,
+    model::Snapshot,
+    HashMap<EID, String>,
+), String> {
     let mut teams = HashMap::new();
+    let mut names = HashMap::new();
     let mut init = model::Snapshot::new();
     for i in 0..units.len() {
         let id = i as EID;
-        let Unit { pos: (x, y), team, weapon } = units[i];
+        let Unit {
+            pos: (x, y),
+            team,
+            weapon,
+            ref loadout,
+            ref name,
+            facing,
+        } = units[i];
         let unit = model::UnitState {
             id,
+            team,
             pos: [x, y],
             vel: [0.0, 0.0],
+            facing: facing.to_radians(),
+            spin: 0.0,
+            mode: model::MoveMode::Walk,
             time: init.time,
 
             weapon,
+            loadout: read_loadout(rules, weapon, loadout)
+                .map_err(|msg| format!("units[{}] {}", i, msg))?,
             action: model::Action::Mobile,
             target_id: NULL_ID,
             target_loc: [0.0, 0.0],
+            health: model::FULL_HEALTH,
         };
         init.states.insert(id, unit);
         teams.insert(id, team);
+        if let Some(name) = name.clone() {
+            names.insert(id, name);
+        }
+    }
+    Ok((teams, init, names))
+}
+
+// doors are added after the units, so that unit ids match their order in the
+// scenario
+fn read_doors(init: &mut model::Snapshot, doors: Vec<Door>) {
+    for Door { from: (x1, y1), to: (x2, y2), open } in doors {
+        let id = init.next_id();
+        let door = model::UnitState {
+            id,
+            team: NULL_TEAM,
+            pos: [x1, y1],
+            vel: [0.0, 0.0],
+            facing: 0.0,
+            spin: 0.0,
+            mode: model::MoveMode::Walk,
+            time: init.time,
+
+            weapon: model::Weapon::Melee,
+            loadout: [None; model::LOADOUT_SIZE],
+            action: if open {
+                model::Action::Open
+            } else {
+                model::Action::Closed
+            },
+            target_id: NULL_ID,
+            target_loc: [x2, y2],
+            health: model::FULL_HEALTH,
+        };
+        init.states.insert(id, door);
     }
-    (teams, init)
 }
 
-fn read_map(map: Vec<Vec<Vec2>>) -> path::Map {
+// units start with a full magazine and reserve for each weapon they carry
+fn read_loadout(
+    rules: &model::Rules,
+    weapon: model::Weapon,
+    rest: &Vec<model::Weapon>,
+) -> Result<model::Loadout, String> {
+    let mut loadout = [None; model::LOADOUT_SIZE];
+    let mut weapons = vec![weapon];
+    for &other in rest {
+        if !weapons.contains(&other) {
+            weapons.push(other);
+        }
+    }
+    if weapons.len() > model::LOADOUT_SIZE {
+        return Err(format!(
+            "carries {} weapons, but units can carry at most {}",
+            weapons.len(),
+            model::LOADOUT_SIZE,
+        ));
+    }
+    for i in 0..weapons.len() {
+        let stats = rules.weapon(weapons[i]);
+        loadout[i] = Some(model::Slot {
+            weapon: weapons[i],
+            mag: stats.mag_size,
+            reserve: stats.reserve,
+        });
+    }
+    Ok(loadout)
+}
+
+// polygons that can be destroyed get an entity to keep track of their hit
+// points, which comes after the units and doors
+fn read_map(
+    map: Vec<Vec<Vec2>>,
+    polys: Vec<Poly>,
+    init: &mut model::Snapshot,
+) -> Result<path::Map, String> {
+    let walls = map.into_iter().enumerate().map(|(i, points)| {
+        let poly = Poly {
+            material: path::Material::Wall,
+            points,
+            hp: None,
+        };
+        (format!("map[{}]", i), poly)
+    });
+    let polys = polys.into_iter().enumerate().map(|(i, poly)| {
+        (format!("polys[{}]", i), poly)
+    });
     let mut result = path::Map::new();
-    for poly in map {
-        for i in 1..poly.len()-1 {
-            result.push([poly[0], poly[i], poly[i+1]]);
+    for (name, Poly { material, points, hp }) in walls.chain(polys) {
+        check_poly(&points).map_err(|msg| format!("{} {}", name, msg))?;
+        let owner = match hp {
+            Some(hp) => read_wall(init, &points, hp),
+            None => NULL_ID,
+        };
+        for trig in triangulate(&points) {
+            result.push((trig, material, owner));
         }
     }
+    Ok(result)
+}
+
+// splits a polygon into a fan of triangles, which only works properly for
+// convex polygons, or polygons that at least have a point that can see every
+// other point
+pub fn triangulate(points: &Vec<Vec2>) -> Vec<path::Trig> {
+    let mut result = Vec::new();
+    for i in 1..points.len().max(2) - 1 {
+        result.push([points[0], points[i], points[i+1]]);
+    }
     result
 }
+
+// polygons get split into a fan of triangles, so they need at least three
+// points, and need to cover some area
+fn check_poly(points: &Vec<Vec2>) -> Result<(), String> {
+    if points.len() < 3 {
+        return Err(format!(
+            "has {} points, but polygons need at least 3",
+            points.len(),
+        ));
+    }
+    let mut area = 0.0;
+    for i in 0..points.len() {
+        let [x1, y1] = points[i];
+        let [x2, y2] = points[(i + 1) % points.len()];
+        area += x1 * y2 - x2 * y1;
+    }
+    if area.abs() < 1e-9 {
+        return Err("has no area".to_string());
+    }
+    Ok(())
+}
+
+fn read_wall(init: &mut model::Snapshot, points: &Vec<Vec2>, hp: f64) -> EID {
+    let id = init.next_id();
+    let mut centre = [0.0, 0.0];
+    for &point in points {
+        centre = vec2_add(centre, vec2_scale(point, 1.0 / points.len() as f64));
+    }
+    let wall = model::UnitState {
+        id,
+        team: NULL_TEAM,
+        pos: centre,
+        vel: [0.0, 0.0],
+        facing: 0.0,
+        spin: 0.0,
+        mode: model::MoveMode::Walk,
+        time: init.time,
+
+        weapon: model::Weapon::Melee,
+        loadout: [None; model::LOADOUT_SIZE],
+        action: model::Action::Wall,
+        target_id: NULL_ID,
+        target_loc: [0.0, 0.0],
+        health: hp,
+    };
+    init.states.insert(id, wall);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polys_need_area() {
+        let square = vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        assert_eq!(check_poly(&square), Ok(()));
+        let line = vec![[0.0, 0.0], [1.0, 1.0]];
+        assert!(check_poly(&line).unwrap_err().contains("2 points"));
+        let flat = vec![[0.0, 0.0], [1.0, 1.0], [2.0, 2.0]];
+        assert_eq!(check_poly(&flat), Err("has no area".to_string()));
+    }
+
+    fn unit_teams(teams: &[TID]) -> HashMap<EID, TID> {
+        teams.iter().enumerate().map(|(i, &t)| (i as EID, t)).collect()
+    }
+
+    #[test]
+    fn teams_without_gaps() {
+        let info = ScenarioInfo::default();
+        assert_eq!(check_teams(&unit_teams(&[0, 1, 1, 0]), &info), Ok(()));
+        let err = check_teams(&unit_teams(&[0, 2, 0, 2]), &info).unwrap_err();
+        assert!(err.starts_with("units[1] is on team 2"));
+    }
+
+    #[test]
+    fn teams_match_info() {
+        let team = Team { name: "Red".to_string(), colour: [1.0, 0.0, 0.0] };
+        let listed = ScenarioInfo {
+            teams: vec![team.clone(), team.clone(), team],
+            ..Default::default()
+        };
+        let err = check_teams(&unit_teams(&[0, 1]), &listed).unwrap_err();
+        assert!(err.starts_with("teams[2] is listed"));
+        let players = ScenarioInfo {
+            players: Some(3),
+            ..Default::default()
+        };
+        assert!(check_teams(&unit_teams(&[0, 1]), &players).is_err());
+        let players = ScenarioInfo {
+            players: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(check_teams(&unit_teams(&[0, 1]), &players), Ok(()));
+    }
+
+    #[test]
+    fn guessed_positions() {
+        let text = "\
+(
+    // Lazer
+    weapon: Lazers,
+    weapon: Lazer,
+)";
+        let msg = "unknown variant `Lazer`, expected one of `Gun`";
+        assert_eq!(guess_pos(text, msg), Some((4, 13)));
+        assert_eq!(guess_pos(text, "missing field `units`"), None);
+        assert_eq!(guess_pos(text, "no name here"), None);
+    }
+
+    #[test]
+    fn found_paths() {
+        let text = "\
+Scenario(
+    // units: [],
+    units: [
+        (team: 0, name: Some(\"a, (b\"), pos: (1.0, 2.0)),
+        (team: 1, pos: (3.0, 4.0)),
+    ],
+    rules: (weapons: {Shotgun: (range: 0.0)}),
+)";
+        assert_eq!(find_path(text, "units"), Some((3, 12)));
+        assert_eq!(find_path(text, "units[1]"), Some((5, 9)));
+        assert_eq!(find_path(text, "units[1].pos"), Some((5, 24)));
+        assert_eq!(find_path(text, "units[2]"), None);
+        let range = "rules.weapons[Shotgun].range";
+        assert_eq!(find_path(text, range), Some((7, 40)));
+        assert_eq!(find_path(text, "map[0]"), None);
+        assert_eq!(find_path(text, "the"), None);
+    }
+
+    #[test]
+    fn semantic_error_positions() {
+        let text = "\
+(
+    units: [
+        (team: 0, pos: (5.0, 5.0), weapon: Gun),
+        (team: 1, pos: (5.0, 5.0), weapon: Gun),
+    ],
+    map: [
+        [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)],
+    ],
+)";
+        let read = |name, text: &str| {
+            read_scenario(&write_temp(name, text)).map(|_| ()).unwrap_err()
+        };
+        let err = read("flat", text);
+        assert!(err.msg.starts_with("map[0] has no area"));
+        assert_eq!(err.pos, Some((7, 9)));
+        let text = text.replace(
+            "(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)",
+            "(3.0, 4.0), (7.0, 4.0), (7.0, 7.0), (3.0, 7.0)",
+        );
+        let err = read("wall", &text);
+        assert!(err.msg.starts_with("units[0] starts inside a wall"));
+        assert_eq!(err.pos, Some((3, 9)));
+    }
+
+    // scenarios are read from files, so tests write theirs out first, named
+    // so that tests running at the same time don't clash
+    fn write_temp(name: &str, text: &str) -> String {
+        let name = format!("tactics-{}-{}", ::std::process::id(), name);
+        let path = ::std::env::temp_dir().join(name);
+        ::std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn read_with_rules(name: &str, rules: &str) -> Result<(), ScenarioError> {
+        let rules_path = write_temp(&format!("{}.rules", name), rules);
+        let rules_file = ::std::path::Path::new(&rules_path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let scenario = format!(
+            "(units: [(team: 0, pos: (5.0, 5.0), weapon: Gun)], map: [], \
+             rules_file: Some({:?}))",
+            rules_file,
+        );
+        let path = write_temp(name, &scenario);
+        read_scenario(&path).map(|_| ())
+    }
+
+    #[test]
+    fn rules_that_stop_time() {
+        assert!(read_with_rules("ok", "(tick_length: 0.05)").is_ok());
+        let slow = "(move_modes: { \
+            Crouch: (speed: 0.0, visibility: 0.25, accuracy: 1.25) })";
+        let err = read_with_rules("slow", slow).unwrap_err();
+        assert!(err.msg.starts_with("move_modes[Crouch].speed is 0"));
+        let blind = "(move_modes: { \
+            Walk: (speed: 1.0, visibility: 1.0, accuracy: -1.0) })";
+        let err = read_with_rules("blind", blind).unwrap_err();
+        assert!(err.msg.starts_with("move_modes[Walk].accuracy is -1"));
+        let stuck = "(terrain: { Mud: 0.0 })";
+        let err = read_with_rules("stuck", stuck).unwrap_err();
+        assert!(err.msg.starts_with("terrain[Mud] is 0"));
+        assert!(err.path.ends_with("stuck.rules"));
+        let frozen = "(turn_speed: 0.0)";
+        let err = read_with_rules("frozen", frozen).unwrap_err();
+        assert!(err.msg.starts_with("turn_speed is 0"));
+        let dropped = "(grenade: (speed: 0.0))";
+        let err = read_with_rules("dropped", dropped).unwrap_err();
+        assert!(err.msg.starts_with("grenade.speed is 0"));
+        let early = "(grenade: (throw_time: -1.0))";
+        let err = read_with_rules("early", early).unwrap_err();
+        assert!(err.msg.starts_with("grenade.throw_time is -1"));
+        let quick = "(weapons: { Shotgun: (range: 0.0) })";
+        let err = read_with_rules("quick", quick).unwrap_err();
+        assert!(err.msg.starts_with("weapons[Shotgun].range is 0"));
+        let stuck = "(door_time: -1.0)";
+        let err = read_with_rules("door", stuck).unwrap_err();
+        assert!(err.msg.starts_with("door_time is -1"));
+        let instant = "(grenade: (fuse: -2.0))";
+        let err = read_with_rules("fuse", instant).unwrap_err();
+        assert!(err.msg.starts_with("grenade.fuse is -2"));
+        assert_eq!(err.pos, Some((1, 18)));
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt;
+
+use prelude::*;
+
+use client::{Command, Plan};
+use model::{MoveMode, Weapon};
+
+// plans are written one unit per line, e.g.
+//   unit 2: nav 10,20; wait 1.5; shoot 3; nav 15,20 crouch
+// anything after a # is a comment
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(self: &Self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub fn parse(text: &str) -> Result<Plan, ParseError> {
+    let mut plan = Plan::new();
+    for (i, line) in text.lines().enumerate() {
+        let err = |message: String| ParseError { line: i + 1, message };
+        let line = line.split('#').next().unwrap().trim();
+        if line.len() == 0 {
+            continue;
+        }
+        let (id, commands) = parse_line(line).map_err(&err)?;
+        let unit_plan = plan.entry(id).or_insert(Vec::new());
+        for command in commands.split(';') {
+            let command = command.trim();
+            if command.len() > 0 {
+                unit_plan.push(parse_command(command).map_err(&err)?);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn parse_line(line: &str) -> Result<(EID, &str), String> {
+    let colon = line.find(':')
+        .ok_or(format!("Expected `unit <id>:` at the start of \"{}\"", line))?;
+    let (head, rest) = line.split_at(colon);
+    let mut words = head.split_whitespace();
+    if words.next() != Some("unit") {
+        return Err(format!("Expected `unit <id>:` but got \"{}\"", head));
+    }
+    let id = words.next().ok_or("Missing unit id".to_string())?;
+    let id = parse_id(id)?;
+    if let Some(extra) = words.next() {
+        return Err(format!("Unexpected \"{}\" after unit id", extra));
+    }
+    Ok((id, &rest[1..]))
+}
+
+fn parse_command(command: &str) -> Result<Command, String> {
+    let mut words = command.splitn(2, char::is_whitespace);
+    let name = words.next().unwrap();
+    let arg = words.next().unwrap_or("").trim();
+    match name {
+        "nav" => parse_nav(arg),
+        "wait" => Ok(Command::Wait(parse_duration(arg)?)),
+        "shoot" => Ok(Command::Shoot(parse_id(arg)?)),
+        "charge" => Ok(Command::Charge(parse_id(arg)?)),
+        "throw" => Ok(Command::Throw(parse_vec(arg)?)),
+        "face" => Ok(Command::Face(parse_num(arg)?)),
+        "suppress" => {
+            let (pos, duration) = parse_area(arg)?;
+            Ok(Command::Suppress(pos, duration))
+        },
+        "overwatch" => {
+            let (pos, duration) = parse_area(arg)?;
+            Ok(Command::Overwatch(pos, duration))
+        },
+        "operate" => Ok(Command::Operate(parse_id(arg)?)),
+        "switch" => Ok(Command::Switch(parse_weapon(arg)?)),
+        "reload" => if arg.len() == 0 {
+            Ok(Command::Reload)
+        } else {
+            Err(format!("Unexpected \"{}\" after reload", arg))
+        },
+        _ => Err(format!("Unknown command \"{}\"", name)),
+    }
+}
+
+// the mode can be left out, in which case the unit walks
+fn parse_nav(arg: &str) -> Result<Command, String> {
+    let mut words = arg.rsplitn(2, char::is_whitespace);
+    let last = words.next().unwrap();
+    let rest = words.next().unwrap_or("").trim();
+    let mode = match last {
+        "sprint" => Some(MoveMode::Sprint),
+        "walk" => Some(MoveMode::Walk),
+        "crouch" => Some(MoveMode::Crouch),
+        _ => None,
+    };
+    match mode {
+        Some(mode) => Ok(Command::Nav(parse_vec(rest)?, mode)),
+        None => Ok(Command::Nav(parse_vec(arg)?, MoveMode::Walk)),
+    }
+}
+
+// a position followed by a duration
+fn parse_area(arg: &str) -> Result<(Vec2, f64), String> {
+    let mut words = arg.rsplitn(2, char::is_whitespace);
+    let duration = words.next().unwrap();
+    let pos = words.next().ok_or(format!(
+        "Expected a position and a duration but got \"{}\"",
+        arg,
+    ))?;
+    Ok((parse_vec(pos)?, parse_duration(duration)?))
+}
+
+fn parse_weapon(word: &str) -> Result<Weapon, String> {
+    match word {
+        "gun" => Ok(Weapon::Gun),
+        "shotgun" => Ok(Weapon::Shotgun),
+        "sniper" => Ok(Weapon::Sniper),
+        "melee" => Ok(Weapon::Melee),
+        _ => Err(format!("Expected a weapon but got \"{}\"", word)),
+    }
+}
+
+fn parse_id(word: &str) -> Result<EID, String> {
+    word.parse()
+        .map_err(|_| format!("Expected a unit id but got \"{}\"", word))
+}
+
+fn parse_num(word: &str) -> Result<f64, String> {
+    let val: f64 = word.parse()
+        .map_err(|_| format!("Expected a number but got \"{}\"", word))?;
+    if val.is_finite() {
+        Ok(val)
+    } else {
+        Err(format!("Expected a finite number but got \"{}\"", word))
+    }
+}
+
+// commands that last no time at all would never finish
+fn parse_duration(word: &str) -> Result<f64, String> {
+    let val = parse_num(word)?;
+    if val > 0.0 {
+        Ok(val)
+    } else {
+        Err(format!("Expected a positive duration but got \"{}\"", word))
+    }
+}
+
+fn parse_vec(arg: &str) -> Result<Vec2, String> {
+    let mut coords = arg.split(',');
+    let x = coords.next().unwrap().trim();
+    let y = coords.next()
+        .ok_or(format!("Expected a position x,y but got \"{}\"", arg))?
+        .trim();
+    if coords.next().is_some() {
+        return Err(format!("Expected a position x,y but got \"{}\"", arg));
+    }
+    Ok([parse_num(x)?, parse_num(y)?])
+}
+
+pub fn print_command(command: Command) -> String {
+    match command {
+        Command::Nav(pos, MoveMode::Walk) => {
+            format!("nav {},{}", pos[0], pos[1])
+        },
+        Command::Nav(pos, mode) => {
+            let mode = match mode {
+                MoveMode::Sprint => "sprint",
+                MoveMode::Walk => "walk",
+                MoveMode::Crouch => "crouch",
+            };
+            format!("nav {},{} {}", pos[0], pos[1], mode)
+        },
+        Command::Wait(duration) => format!("wait {}", duration),
+        Command::Shoot(target) => format!("shoot {}", target),
+        Command::Charge(target) => format!("charge {}", target),
+        Command::Throw(pos) => format!("throw {},{}", pos[0], pos[1]),
+        Command::Face(angle) => format!("face {}", angle),
+        Command::Suppress(pos, duration) => {
+            format!("suppress {},{} {}", pos[0], pos[1], duration)
+        },
+        Command::Overwatch(pos, duration) => {
+            format!("overwatch {},{} {}", pos[0], pos[1], duration)
+        },
+        Command::Switch(weapon) => {
+            let weapon = match weapon {
+                Weapon::Gun => "gun",
+                Weapon::Shotgun => "shotgun",
+                Weapon::Sniper => "sniper",
+                Weapon::Melee => "melee",
+            };
+            format!("switch {}", weapon)
+        },
+        Command::Reload => "reload".to_string(),
+        Command::Operate(door) => format!("operate {}", door),
+    }
+}
+
+// units with nothing planned are left out, and units are printed in order of
+// id so that saved plans diff nicely
+pub fn print(plan: &Plan) -> String {
+    let mut ids: Vec<EID> = plan
+        .iter()
+        .filter(|&(_, commands)| commands.len() > 0)
+        .map(|(&id, _)| id)
+        .collect();
+    ids.sort();
+
+    let mut result = String::new();
+    for id in ids {
+        let commands: Vec<String> = plan[&id]
+            .iter()
+            .map(|&command| print_command(command))
+            .collect();
+        result.push_str(&format!("unit {}: {}\n", id, commands.join("; ")));
+    }
+    result
+}
+
+pub fn read_plan(path: &str) -> Result<Plan, String> {
+    let text = ::std::fs::read_to_string(path)
+        .map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    parse(&text).map_err(|err| format!("{}: {}", path, err))
+}
+
+pub fn write_plan(path: &str, plan: &Plan) -> Result<(), String> {
+    ::std::fs::write(path, print(plan))
+        .map_err(|err| format!("Couldn't write {}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let text = "\
+unit 0: nav 10,20; wait 1.5; shoot 3; nav 15,20 crouch
+unit 2: throw 4,5; face 90; suppress 30,40 5; overwatch 1,2 3.5
+unit 7: switch sniper; reload; operate 4; nav 0,0 sprint; charge 2
+";
+        let plan = parse(text).unwrap();
+        assert_eq!(plan[&0][2], Command::Shoot(3));
+        assert_eq!(plan[&0][3], Command::Nav([15.0, 20.0], MoveMode::Crouch));
+        assert_eq!(print(&plan), text);
+        assert_eq!(parse(&print(&plan)).unwrap(), plan);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let plan = parse("# nothing yet\n\nunit 1: wait 2 # then stop\n");
+        let plan = plan.unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[&1], vec![Command::Wait(2.0)]);
+    }
+
+    fn error(text: &str) -> ParseError {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn missing_colon() {
+        let err = error("unit 0: wait 1\nunit 1 wait 1");
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("unit <id>:"));
+    }
+
+    #[test]
+    fn malformed_vector() {
+        assert_eq!(error("unit 0: nav 10").line, 1);
+        assert!(error("unit 0: nav 10").message.contains("x,y"));
+        assert!(error("unit 0: throw 1,2,3").message.contains("x,y"));
+        assert!(error("unit 0: nav 1,x").message.contains("number"));
+        assert!(error("unit 0: nav inf,2").message.contains("finite"));
+    }
+
+    #[test]
+    fn durations_are_positive() {
+        assert!(error("unit 0: wait 0").message.contains("positive"));
+        assert!(error("unit 0: wait -1").message.contains("positive"));
+        let err = error("unit 0: suppress 1,2 -3");
+        assert_eq!(err.message, "Expected a positive duration but got \"-3\"");
+        assert!(error("unit 0: overwatch 1,2 0").message.contains("positive"));
+        assert!(parse("unit 0: face -90; wait 0.5").is_ok());
+    }
+
+    #[test]
+    fn unknown_command() {
+        let err = error("unit 0: nav 1,2; jump 3");
+        assert_eq!(err.line, 1);
+        assert_eq!(err.message, "Unknown command \"jump\"");
+    }
+}
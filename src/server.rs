@@ -1,71 +1,456 @@
+use vecmath;
+
 use prelude::*;
 
 use model;
 use path;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 enum Effect {
     Die,
+    Damage(f64),
     StopMoving,
     StopShooting,
+    StopThrowing,
+    StopSwitching,
+    // runs out of rounds, and starts reloading if there are any to spare
+    Reload,
+    StopReloading,
+    StopOperating,
+    // opens a closed door, or closes an open one
+    Toggle,
+    // starts shooting at a unit that was at the given position
+    Engage(EID, Vec2),
+    Detonate,
+    // brings a new entity into the simulation, rather than changing one
+    Spawn(model::UnitState),
 }
 
 pub struct Server {
     pub current: model::Snapshot,
     pub map: path::Map,
+    pub rules: model::Rules,
 }
 
 impl Server {
-    pub fn new(current: model::Snapshot, map: path::Map) -> Self {
-        Server { current, map }
+    pub fn new(
+        current: model::Snapshot,
+        map: path::Map,
+        rules: model::Rules,
+    ) -> Self {
+        Server { current, map, rules }
     }
 
-    fn consequence(self: &Self, next: f64) -> Vec<(f64, EID, Effect)> {
+    fn consequence(self: &Self, next: Time) -> Vec<(Time, EID, Effect)> {
         let mut result = Vec::new();
         result.extend(self.consequence_tools());
         result.extend(self.consequence_boundaries(next));
         result
     }
 
-    fn consequence_tools(self: &Self) -> Vec<(f64, EID, Effect)> {
+    fn consequence_tools(self: &Self) -> Vec<(Time, EID, Effect)> {
+        let mut result = Vec::new();
+        let mut throws = Vec::new();
+        for (&id, &unit) in &self.current.states {
+            match unit.action {
+                model::Action::Throw => {
+                    let time = unit.time + self.rules.throw_time();
+                    throws.push((time, id));
+                },
+                model::Action::Switch => {
+                    let time = unit.time + self.rules.swap_time(unit.weapon);
+                    result.push((time, id, Effect::StopSwitching));
+                },
+                model::Action::Reload => {
+                    let time = unit.time + self.rules.reload_time(unit.weapon);
+                    result.push((time, id, Effect::StopReloading));
+                },
+                model::Action::Operate => {
+                    let time = unit.time + self.rules.door_time();
+                    let door = self.current.states[&unit.target_id];
+                    result.push((time, id, Effect::StopOperating));
+                    // doors won't close on anyone standing in the doorway
+                    let closing = door.action == model::Action::Open;
+                    if !closing || !self.doorway_occupied(door, time) {
+                        result.push((time, door.id, Effect::Toggle));
+                    }
+                },
+                model::Action::Grenade(detonation) => {
+                    if unit.vel != [0.0, 0.0] {
+                        let flight = self.rules.flight_time(
+                            unit.pos,
+                            unit.target_loc,
+                        );
+                        let time = unit.time + flight;
+                        result.push((time, id, Effect::StopMoving));
+                    }
+                    result.push((detonation, id, Effect::Detonate));
+                    result.extend(self.blast(unit, detonation));
+                },
+                _ => (),
+            }
+        }
+        for (&id, &unit) in &self.current.states {
+            if !unit.action.can_be_hurt() {
+                continue;
+            }
+            if let Some(time) = self.death_time(unit) {
+                result.push((time, id, Effect::Die));
+            }
+            if let Some(ticks) = self.rules.empty_time(&unit) {
+                let time = unit.time + ticks;
+                let spare = self.rules
+                    .ammo_at(&unit, time)
+                    .iter()
+                    .filter_map(|&slot| slot)
+                    .any(|slot| slot.weapon == unit.weapon && slot.reserve > 0);
+                if spare {
+                    result.push((time, id, Effect::Reload));
+                } else {
+                    result.push((time, id, Effect::StopShooting));
+                }
+            }
+        }
+        // grenades get ids in the order they are thrown, so that clients
+        // simulating the same throws come up with the same ids
+        throws.sort();
+        let mut next_id = self.current.next_id();
+        for (time, id) in throws {
+            let mut thrower = self.current.states[&id];
+            thrower.update_pos(time);
+            let grenade = self.rules.grenade_state(next_id, thrower);
+            next_id += 1;
+            result.push((time, id, Effect::StopThrowing));
+            result.push((time, grenade.id, Effect::Spawn(grenade)));
+        }
+        result
+    }
+
+    // the ticks during which a unit is in another unit's line of suppressing
+    // fire, assuming they both keep doing what they are doing now, counting
+    // from when damage stopped being counted in the unit's health
+    fn lane_time(
+        self: &Self,
+        shooter: model::UnitState,
+        unit: model::UnitState,
+    ) -> Option<(Time, Option<Time>)> {
+        if shooter.action != model::Action::Suppress
+            || shooter.team == unit.team
+            || !unit.action.is_unit()
+        {
+            return None;
+        }
+        let start = ::std::cmp::max(shooter.time, unit.time);
+        let mut unit = unit;
+        unit.update_pos(start);
+        let (enter, exit) = path::capsule_times(
+            shooter.pos,
+            shooter.target_loc,
+            self.rules.suppress_width,
+            unit.pos,
+            unit.vel,
+        )?;
+        // only count whole ticks spent in the lane
+        let enter = start + enter.max(0.0).ceil() as Ticks;
+        if exit.is_infinite() {
+            return Some((enter, None));
+        }
+        let exit = start + exit.ceil() as Ticks;
+        if exit > enter {
+            Some((enter, Some(exit)))
+        } else {
+            None
+        }
+    }
+
+    // periods during which the given unit is being shot at, and how much
+    // damage it takes per tick, for damage that isn't already counted in its
+    // health
+    fn incoming(self: &Self, unit: model::UnitState)
+        -> Vec<(Time, Option<Time>, f64)>
+    {
+        let mut result = Vec::new();
+        for (_, &shooter) in &self.current.states {
+            let rate = self.rules.damage_rate(&shooter);
+            if shooter.action == model::Action::Shoot
+                && shooter.target_id == unit.id
+            {
+                let start = ::std::cmp::max(shooter.time, unit.time);
+                result.push((start, None, rate));
+            } else if let Some((start, end)) = self.lane_time(shooter, unit) {
+                result.push((start, end, rate));
+            }
+        }
+        result
+    }
+
+    fn health_at(self: &Self, id: EID, time: Time) -> f64 {
+        let unit = self.current.states[&id];
+        let mut health = unit.health;
+        for (start, end, rate) in self.incoming(unit) {
+            let end = match end {
+                Some(end) if end < time => end,
+                _ => time,
+            };
+            if end > start {
+                health -= rate * (end - start) as f64;
+            }
+        }
+        health
+    }
+
+    // health goes down piecewise linearly as shooters join in and leave off
+    fn death_time(self: &Self, unit: model::UnitState) -> Option<Time> {
+        let mut changes = Vec::new();
+        for (start, end, rate) in self.incoming(unit) {
+            changes.push((start, rate));
+            if let Some(end) = end {
+                changes.push((end, -rate));
+            }
+        }
+        changes.sort_by_key(|&(time, _)| time);
+        // adding and removing rates leaves rounding error behind
+        let min_rate = 1e-12;
+        let mut health = unit.health;
+        let mut rate = 0.0;
+        let mut time = unit.time;
+        for (at, change) in changes {
+            if rate > min_rate {
+                let death = time + Self::ticks_to_kill(health, rate);
+                if death <= at {
+                    return Some(death);
+                }
+            }
+            health -= rate * (at - time) as f64;
+            rate += change;
+            time = at;
+        }
+        if rate > min_rate {
+            Some(time + Self::ticks_to_kill(health, rate))
+        } else {
+            None
+        }
+    }
+
+    fn ticks_to_kill(health: f64, rate: f64) -> Ticks {
+        // ignore rounding error, the same way that Rules::ticks does
+        ((health / rate - 1e-9).ceil() as Ticks).max(1)
+    }
+
+    fn doorway_occupied(self: &Self, door: model::UnitState, time: Time)
+        -> bool
+    {
+        self.current.states.values().any(|&unit| {
+            let mut unit = unit;
+            unit.update_pos(time);
+            unit.action.is_unit()
+                && unit.action != model::Action::Dead
+                && Self::in_doorway(&self.rules, door, unit.pos)
+        })
+    }
+
+    fn in_doorway(rules: &model::Rules, door: model::UnitState, pos: Vec2)
+        -> bool
+    {
+        let radius = rules.door_thickness / 2.0;
+        path::in_capsule(door.pos, door.target_loc, radius, pos)
+    }
+
+    // whether a position is inside a closed door
+    fn in_door(rules: &model::Rules, state: &model::Snapshot, pos: Vec2)
+        -> bool
+    {
+        state.states.values().any(|&door| {
+            door.action == model::Action::Closed
+                && Self::in_doorway(rules, door, pos)
+        })
+    }
+
+    // whether a closed door cuts the line between two positions
+    fn door_in_way(state: &model::Snapshot, p1: Vec2, p2: Vec2) -> bool {
+        state.states.values().any(|door| {
+            door.action == model::Action::Closed
+                && path::lines_cross(p1, p2, door.pos, door.target_loc)
+        })
+    }
+
+    // units and walls caught in the blast of a grenade, assuming it keeps
+    // moving the way it is now until it goes off
+    fn blast(
+        self: &Self,
+        grenade: model::UnitState,
+        detonation: Time,
+    ) -> Vec<(Time, EID, Effect)> {
         let mut result = Vec::new();
-        for (_, &unit) in &self.current.states {
-            if unit.action == model::Action::Shoot {
-                let target = unit.target_id;
-                let curr = self.current.states[&target];
-                if curr.action != model::Action::Dead {
-                    result.push((unit.time + 5.0, target, Effect::Die)); }
+        let mut grenade = grenade;
+        grenade.update_pos(detonation);
+        let radius = self.rules.grenade.blast_radius;
+        for (&id, &unit) in &self.current.states {
+            if !unit.action.can_be_hurt() {
+                continue;
+            }
+            let mut unit = unit;
+            unit.update_pos(detonation);
+            let dist = vecmath::vec2_len(vec2_sub(unit.pos, grenade.pos));
+            let caught = if unit.action == model::Action::Wall {
+                path::near_wall(&self.map, id, grenade.pos, radius)
+            } else {
+                dist <= radius
+                    && path::blast_reaches_pos(&self.map, grenade.pos, unit.pos)
+                    && !Self::door_in_way(&self.current, grenade.pos, unit.pos)
+            };
+            if caught {
+                let damage = self.rules.grenade.damage;
+                result.push((detonation, id, Effect::Damage(damage)));
             }
         }
         result
     }
 
-    fn consequence_boundaries(self: &Self, next: f64)
-        -> Vec<(f64, EID, Effect)>
+    fn consequence_boundaries(self: &Self, next: Time)
+        -> Vec<(Time, EID, Effect)>
     {
         let mut result = Vec::new();
         let future = model::Timeline::new(); // the future is unknown :)
         let mut time = self.current.time;
-        while result.len() == 0 && time < next + 0.1 {
+        while result.len() == 0 && time < next + 1 {
             let mut state = self.current.clone();
-            time += 0.1;
+            time += 1;
             state.update(&future, time);
             for (&id, &unit) in &state.states {
-                let eff = Self::unit_collision(&self.map, &state, unit);
+                let eff = Self::unit_collision(
+                    &self.map,
+                    &self.rules,
+                    &state,
+                    unit,
+                );
                 if let Some((eff_time, effect)) = eff {
                     result.push((eff_time, id, effect));
                 }
+                if let Some(effect) = self.watch(&state, unit) {
+                    result.push((time, id, effect));
+                }
             }
         }
         result
     }
 
+    // units on overwatch shoot at the nearest enemy that they can see and
+    // reach with their weapon
+    fn watch(
+        self: &Self,
+        state: &model::Snapshot,
+        unit: model::UnitState,
+    ) -> Option<Effect> {
+        if unit.action != model::Action::Overwatch {
+            return None;
+        }
+        let range = self.rules.weapon(unit.weapon).range;
+        let mut nearest: Option<(f64, EID, Vec2)> = None;
+        for (&id, other) in &state.states {
+            if other.team == unit.team
+                || !other.action.is_unit()
+                || other.action == model::Action::Dead
+                || !Self::can_see(&self.map, &self.rules, state, &unit, other)
+            {
+                continue;
+            }
+            let dist = vecmath::vec2_len(vec2_sub(other.pos, unit.pos));
+            if dist > range {
+                continue;
+            }
+            // ties go to the lowest id, so that clients agree with the server
+            let closer = match nearest {
+                None => true,
+                Some((best, best_id, _)) => {
+                    dist < best || (dist == best && id < best_id)
+                },
+            };
+            if closer {
+                nearest = Some((dist, id, other.pos));
+            }
+        }
+        nearest.map(|(_, id, pos)| Effect::Engage(id, pos))
+    }
+
+    // whether one unit can see another, for fog of war
+    pub fn can_see(
+        map: &path::Map,
+        rules: &model::Rules,
+        state: &model::Snapshot,
+        unit: &model::UnitState,
+        other: &model::UnitState,
+    ) -> bool {
+        let dist = vecmath::vec2_len(vec2_sub(other.pos, unit.pos));
+        rules.in_view(unit, other.pos)
+            && dist <= rules.visible_range(other)
+            && path::unit_can_see_pos(map, unit.pos, other.pos)
+            && !Self::door_in_way(state, unit.pos, other.pos)
+            && !Self::units_in_way(rules, state, unit, other)
+    }
+
+    // other units standing between two units, when the rules say that units
+    // block sight
+    fn units_in_way(
+        rules: &model::Rules,
+        state: &model::Snapshot,
+        unit: &model::UnitState,
+        other: &model::UnitState,
+    ) -> bool {
+        if !rules.units_block_sight {
+            return false;
+        }
+        state.states.values().any(|&blocker| {
+            if blocker.id == unit.id
+                || blocker.id == other.id
+                || !blocker.action.is_unit()
+                || blocker.action == model::Action::Dead
+            {
+                return false;
+            }
+            let mut blocker = blocker;
+            blocker.update_pos(unit.time);
+            path::in_capsule(
+                unit.pos,
+                other.pos,
+                rules.unit_radius,
+                blocker.pos,
+            )
+        })
+    }
+
+    // whether a moving unit is about to walk into another unit, in which
+    // case it stops a tick early, so that the two never overlap
+    fn unit_blocked(
+        rules: &model::Rules,
+        state: &model::Snapshot,
+        unit: model::UnitState,
+    ) -> bool {
+        let mut unit = unit;
+        unit.update_pos(unit.time + 1);
+        state.states.values().any(|&other| {
+            if other.id == unit.id
+                || !other.action.is_unit()
+                || other.action == model::Action::Dead
+            {
+                return false;
+            }
+            let mut other = other;
+            other.update_pos(unit.time);
+            let disp = vec2_sub(other.pos, unit.pos);
+            let closing = vec2_sub(other.vel, unit.vel);
+            vecmath::vec2_len(disp) < 2.0 * rules.unit_radius
+                && vecmath::vec2_dot(disp, closing) < 0.0
+        })
+    }
+
     pub fn collision_imminent(
         map: &path::Map,
+        rules: &model::Rules,
         state: &model::Snapshot,
         unit: model::UnitState,
     ) -> bool {
-        Self::unit_collision(map, state, unit).is_some()
+        Self::unit_collision(map, rules, state, unit).is_some()
     }
 
     // one of 3 causes of changes in state.
@@ -79,25 +464,124 @@ impl Server {
     // a problem for design complexity)
     fn unit_collision(
         map: &path::Map,
+        rules: &model::Rules,
         state: &model::Snapshot,
         unit: model::UnitState,
-    ) -> Option<(f64, Effect)> {
+    ) -> Option<(Time, Effect)> {
         if unit.vel != [0.0; 2] {
-            if path::unit_is_in_wall(&map, unit.pos) {
-                return Some((unit.time - 0.1, Effect::StopMoving));
+            // grenades fly over units and low cover
+            let in_wall = if unit.action.is_unit() {
+                path::unit_is_in_wall(&map, unit.pos)
+            } else {
+                path::grenade_is_in_wall(&map, unit.pos)
+            };
+            if in_wall || Self::in_door(rules, state, unit.pos) {
+                return Some((unit.time - 1, Effect::StopMoving));
+            }
+            if unit.action.is_unit()
+                && Self::unit_blocked(rules, state, unit)
+            {
+                return Some((unit.time, Effect::StopMoving));
+            }
+            // units slow down before walking onto slow ground
+            if unit.action.is_unit() {
+                let ahead = vec2_add(unit.pos, vec2_scale(unit.vel, 0.5));
+                let speed = rules.speed(rules.move_mode(unit.mode).speed)
+                    * rules.terrain_speed(map, ahead);
+                if vecmath::vec2_len(unit.vel) > speed * (1.0 + 1e-9) {
+                    return Some((unit.time, Effect::StopMoving));
+                }
             }
         }
         use model::Action::*;
         match unit.action {
-            Mobile | Dead => (),
-            Shoot => {
-                let target = state.states[&unit.target_id].pos;
-                let can_see = path::unit_can_see_pos(
+            Mobile | Switch | Reload | Dead => (),
+            Grenade(_) | Detonated | Open | Closed | Wall | Rubble => (),
+            Operate => {
+                let door = state.states.get(&unit.target_id);
+                let reach = match door {
+                    Some(&door) if door.action.is_door() => path::in_capsule(
+                        door.pos,
+                        door.target_loc,
+                        rules.door_reach,
+                        unit.pos,
+                    ),
+                    _ => false,
+                };
+                if !reach || unit.vel != [0.0; 2] {
+                    return Some((unit.time, Effect::StopOperating));
+                }
+            },
+            Overwatch => {
+                if unit.spin != 0.0 || unit.vel != [0.0; 2] {
+                    return Some((unit.time, Effect::StopShooting));
+                }
+            },
+            Suppress => {
+                let stats = rules.weapon(unit.weapon);
+                let disp = vec2_sub(unit.target_loc, unit.pos);
+                let can_see = !stats.needs_los || path::unit_can_see_pos(
                     &map,
                     unit.pos,
-                    target,
-                );
-                if !can_see {
+                    unit.target_loc,
+                ) && !Self::door_in_way(state, unit.pos, unit.target_loc);
+                // suppressing units hold their position, so that the line of
+                // fire stays put
+                if !can_see
+                    || !rules.in_view(&unit, unit.target_loc)
+                    || unit.spin != 0.0
+                    || unit.vel != [0.0; 2]
+                    || vecmath::vec2_len(disp) > stats.range
+                {
+                    return Some((unit.time, Effect::StopShooting));
+                }
+            },
+            Throw => {
+                let disp = vec2_sub(unit.target_loc, unit.pos);
+                let dist = vecmath::vec2_len(disp);
+                // allow for rounding when the client shortens a long throw
+                let range = rules.grenade.range * (1.0 + 1e-9);
+                if unit.vel != [0.0; 2] || dist > range {
+                    return Some((unit.time, Effect::StopThrowing));
+                }
+            },
+            Shoot => {
+                let stats = rules.weapon(unit.weapon);
+                // state might be older than the unit, e.g. when validating
+                // there's nothing left to shoot at
+                let mut target = match state.states.get(&unit.target_id) {
+                    Some(&target) => target,
+                    None => return Some((unit.time, Effect::StopShooting)),
+                };
+                if target.action == Dead || target.action == Rubble {
+                    return Some((unit.time, Effect::StopShooting));
+                }
+                target.update_pos(unit.time);
+                let dist = vecmath::vec2_len(vec2_sub(target.pos, unit.pos));
+                let speed = vecmath::vec2_len(unit.vel);
+                let in_sight = if target.action == Wall {
+                    path::unit_can_see_wall(
+                        &map,
+                        unit.pos,
+                        target.id,
+                        target.pos,
+                    )
+                } else {
+                    path::unit_can_see_pos(&map, unit.pos, target.pos)
+                };
+                let can_see = !stats.needs_los || in_sight
+                    && !Self::door_in_way(state, unit.pos, target.pos);
+                // units can't turn while they shoot, so targets can escape
+                // by leaving the shooter's field of view
+                let in_way = Self::units_in_way(rules, state, &unit, &target);
+                if !can_see
+                    || in_way
+                    || !rules.in_view(&unit, target.pos)
+                    || dist > rules.visible_range(&target)
+                    || unit.spin != 0.0
+                    || dist > stats.range
+                    || speed > rules.speed(stats.fire_speed) * (1.0 + 1e-9)
+                {
                     return Some((unit.time, Effect::StopShooting));
                 }
             },
@@ -116,6 +600,11 @@ impl Server {
             sorted_states.insert(unit);
         }
         let mut snap = sorted_states.first();
+        for (&id, unit) in &mut snap.states {
+            let old = self.current.states[&id];
+            unit.health = self.health_at(id, unit.time);
+            unit.loadout = self.rules.ammo_at(&old, unit.time);
+        }
 
         let ext = self.consequence(snap.time);
 
@@ -128,11 +617,20 @@ impl Server {
                 snap = model::Snapshot::with_time(time);
             }
             if snap.time == time {
+                if let Effect::Spawn(state) = effect {
+                    snap.insert(state);
+                    continue;
+                }
                 let mut state = snap
                     .states
                     .get(&id)
                     .cloned()
-                    .unwrap_or_else(|| self.current.states[&id]);
+                    .unwrap_or_else(|| {
+                        let mut state = self.current.states[&id];
+                        state.health = self.health_at(id, time);
+                        state.loadout = self.rules.ammo_at(&state, time);
+                        state
+                    });
                 Self::apply_effects(
                     &mut state,
                     time,
@@ -142,6 +640,16 @@ impl Server {
             }
         }
 
+        self.settle_targets(&mut snap);
+
+        // clients take destroyed walls out of their maps when they see the
+        // rubble, in Client::accept_outcome
+        for (&id, unit) in &snap.states {
+            if unit.action == model::Action::Rubble {
+                path::destroy(&mut self.map, id);
+            }
+        }
+
         // else its time field is probably 0.0 which we might not want
         if snap.states.len() > 0 {
             self.current.time = snap.time;
@@ -151,8 +659,106 @@ impl Server {
         Ok(snap)
     }
 
+    // units that a shooter has done damage to that isn't counted in their
+    // health yet
+    fn damaged_by(self: &Self, shooter: model::UnitState, time: Time)
+        -> Vec<EID>
+    {
+        match shooter.action {
+            model::Action::Shoot => vec![shooter.target_id],
+            model::Action::Suppress => self.current
+                .states
+                .values()
+                .filter(|&&unit| match self.lane_time(shooter, unit) {
+                    Some((start, _)) => start < time,
+                    None => false,
+                })
+                .map(|unit| unit.id)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // damage is only added to a unit's health when a new state is written for
+    // it, so when a shooter's state changes, its targets need new states too
+    fn settle_targets(self: &Self, snap: &mut model::Snapshot) {
+        let mut changed: Vec<EID> = snap.states.keys().cloned().collect();
+        while let Some(id) = changed.pop() {
+            let old = match self.current.states.get(&id) {
+                Some(&old) => old,
+                None => continue,
+            };
+            for target in self.damaged_by(old, snap.time) {
+                if snap.states.contains_key(&target) {
+                    continue;
+                }
+                let mut state = self.current.states[&target];
+                if !state.action.can_be_hurt() {
+                    continue;
+                }
+                state.health = self.health_at(target, snap.time);
+                state.loadout = self.rules.ammo_at(&state, snap.time);
+                state.update_pos(snap.time);
+                snap.insert(state);
+                changed.push(target);
+            }
+        }
+    }
+
     fn is_valid(self: &Self, unit: model::UnitState) -> bool {
-        if Self::collision_imminent(&self.map, &self.current, unit) {
+        let old = match self.current.states.get(&unit.id) {
+            Some(&old) => old,
+            None => return false,
+        };
+        if old.action == model::Action::Dead || !old.action.is_unit() {
+            return false;
+        }
+        if !unit.action.is_unit() {
+            return false;
+        }
+        if !Self::is_valid_weapon(&self.rules, old, unit) {
+            return false;
+        }
+        if unit.action == model::Action::Shoot {
+            let target = match self.current.states.get(&unit.target_id) {
+                Some(&target) => target,
+                None => return false,
+            };
+            let wall = target.action == model::Action::Wall;
+            let dead = target.action == model::Action::Dead;
+            if dead || !target.action.is_unit() && !wall {
+                return false;
+            }
+            let continuing = old.action == model::Action::Shoot
+                && old.target_id == unit.target_id;
+            let target_loc = if continuing {
+                old.target_loc
+            } else {
+                let mut target = target;
+                target.update_pos(unit.time);
+                target.pos
+            };
+            if unit.target_loc != target_loc {
+                return false;
+            }
+        }
+        // units turn at the spin they already had rather than snapping to a
+        // new facing, which is how next_move works it out in the client
+        let mut turned = old;
+        turned.update_pos(unit.time);
+        if model::angle_between(turned.facing, unit.facing).abs() > 1e-9 {
+            return false;
+        }
+        let rules = &self.rules;
+        // allow for rounding in the client's turn and walk
+        if unit.spin.abs() > rules.max_spin() * (1.0 + 1e-9) {
+            return false;
+        }
+        let speed = rules.speed(rules.move_mode(unit.mode).speed);
+        if vecmath::vec2_len(unit.vel) > speed * (1.0 + 1e-9) {
+            return false;
+        }
+        if Self::collision_imminent(&self.map, rules, &self.current, unit) {
             return false;
         }
         // also position continuity,
@@ -160,36 +766,166 @@ impl Server {
         true
     }
 
+    // units can only change weapons by switching, which can't be cut short
+    // except by switching again, and can only do what their weapon allows
+    // with the ammunition they have left
+    pub fn is_valid_weapon(
+        rules: &model::Rules,
+        old: model::UnitState,
+        unit: model::UnitState,
+    ) -> bool {
+        let weapons = |unit: model::UnitState| {
+            let mut weapons = [None; model::LOADOUT_SIZE];
+            for i in 0..model::LOADOUT_SIZE {
+                weapons[i] = unit.loadout[i].map(|slot| slot.weapon);
+            }
+            weapons
+        };
+        if weapons(unit) != weapons(old) || !unit.carries(unit.weapon) {
+            return false;
+        }
+        let switching = unit.action == model::Action::Switch;
+        if unit.weapon != old.weapon && !switching {
+            return false;
+        }
+        if old.action == model::Action::Switch && !switching {
+            let ready = old.time + rules.swap_time(old.weapon);
+            if unit.time < ready {
+                return false;
+            }
+        }
+        let mut ammo = unit;
+        ammo.loadout = rules.ammo_at(&old, unit.time);
+        let slot = ammo.slot(unit.weapon).unwrap();
+        if rules.uses_ammo(unit.weapon) {
+            let needs_ammo = unit.is_firing()
+                || unit.action == model::Action::Overwatch;
+            if needs_ammo && slot.mag == 0 {
+                return false;
+            }
+            let full = slot.mag >= rules.weapon(unit.weapon).mag_size;
+            if unit.action == model::Action::Reload
+                && (full || slot.reserve == 0)
+            {
+                return false;
+            }
+        } else if unit.action == model::Action::Reload {
+            return false;
+        }
+        unit.weapon.allows(unit.action)
+    }
+
     fn apply_effects(
         state: &mut model::UnitState,
-        time: f64,
+        time: Time,
         effect: Effect,
     ) {
         use self::Effect::*;
         match effect {
             Die => {
                 state.update_pos(time);
-                state.action = model::Action::Dead;
+                state.action = if state.action == model::Action::Wall {
+                    model::Action::Rubble
+                } else {
+                    model::Action::Dead
+                };
                 state.vel = [0.0, 0.0];
+                state.spin = 0.0;
                 state.target_id = NULL_ID;
                 state.target_loc = [0.0, 0.0];
+                state.health = 0.0;
+            },
+            Damage(damage) => {
+                state.update_pos(time);
+                state.health -= damage;
+                if state.health <= 0.0 {
+                    Self::apply_effects(state, time, Die);
+                }
             },
-            StopMoving | StopShooting => {
+            Detonate => {
+                state.update_pos(time);
+                state.action = model::Action::Detonated;
+                state.vel = [0.0, 0.0];
+                state.target_loc = [0.0, 0.0];
+            },
+            Reload => {
+                // units that were shooting on the move stop to reload
+                if state.time < time {
+                    state.update_pos(time);
+                    state.vel = [0.0, 0.0];
+                    state.action = model::Action::Reload;
+                    state.target_id = NULL_ID;
+                    state.target_loc = [0.0, 0.0];
+                }
+            },
+            Toggle => {
+                state.update_pos(time);
+                state.action = if state.action == model::Action::Open {
+                    model::Action::Closed
+                } else {
+                    model::Action::Open
+                };
+            },
+            Engage(target, target_loc) => {
+                if state.time < time {
+                    state.update_pos(time);
+                    state.action = model::Action::Shoot;
+                    state.target_id = target;
+                    state.target_loc = target_loc;
+                }
+            },
+            StopMoving
+            | StopShooting
+            | StopThrowing
+            | StopSwitching
+            | StopReloading
+            | StopOperating => {
                 if state.time < time {
                     state.update_pos(time);
                     match effect {
                         StopMoving => {
                             state.vel = [0.0, 0.0];
                         },
-                        StopShooting => {
+                        StopShooting
+                        | StopThrowing
+                        | StopSwitching
+                        | StopReloading
+                        | StopOperating => {
                             state.target_id = NULL_ID;
                             state.target_loc = [0.0, 0.0];
                             state.action = model::Action::Mobile;
                         },
-                        Die => unreachable!(),
+                        _ => unreachable!(),
                     }
                 }
             },
+            Spawn(_) => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use save;
+
+    fn demo() -> Server {
+        let path = format!("{}/demo", env!("CARGO_MANIFEST_DIR"));
+        let (_, init, map, rules, _, _) = save::read_scenario(&path).unwrap();
+        Server::new(init, map, rules)
+    }
+
+    #[test]
+    fn turning_takes_time() {
+        let mut server = demo();
+        let mut unit = server.current.states[&0];
+        unit.time = unit.time + 1;
+        unit.facing += 1.0;
+        assert_eq!(server.resolve(vec![unit].into_iter()).err(), Some(0));
+
+        let mut unit = server.current.states[&0];
+        unit.time = unit.time + 1;
+        unit.spin = server.rules.max_spin();
+        assert!(server.resolve(vec![unit].into_iter()).is_ok());
+    }
+}
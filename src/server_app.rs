@@ -1,9 +1,11 @@
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::net;
 
 use prelude::*;
 
 use model;
+use path;
 use save;
 use server::Server;
 
@@ -16,6 +18,8 @@ pub struct ServerInstance {
     players: HashMap<TID, net::TcpStream>,
     player_names: HashMap<TID, String>,
     server: Server,
+    bounds: Option<path::Bounds>,
+    info: save::ScenarioInfo,
     // read_timeout: Option<time::Duration>,
 }
 
@@ -26,19 +30,29 @@ impl ServerApp {
         ServerApp { listener }
     }
 
-    pub fn spawn_instance(self: &mut Self, path: &String) -> ServerInstance {
-        let mut instance = ServerInstance::new(path); 
-        for (_unit, team) in instance.teams.clone() {
-            if !instance.players.contains_key(&team) {
-                println!("Waiting for player {}", team);
-                let player = self.listener.accept().unwrap().0;
-                instance.add_player(team, player);
-            }
+    // the scenario is read again for each game, so that it can be edited
+    // between games
+    pub fn spawn_instance(
+        self: &mut Self,
+        path: &String,
+    ) -> Result<ServerInstance, save::ScenarioError> {
+        let mut instance = ServerInstance::new(path)?;
+        if instance.info.title != "" {
+            println!("Loaded {}", instance.info.title);
+        }
+        let mut open: BTreeSet<TID> = instance.teams
+            .values()
+            .cloned()
+            .collect();
+        while open.len() > 0 {
+            println!("Waiting for {} more player(s)", open.len());
+            let player = self.listener.accept().unwrap().0;
+            instance.add_player(&mut open, player);
         }
         // if this errors then the instance thread will probably crash very
         // fast, but that beats the server itself crashing
         let _ = instance.send_roster();
-        instance
+        Ok(instance)
     }
 }
 
@@ -61,26 +75,50 @@ fn delegate<R, F>(mut f: F) -> mpsc::Receiver<R> where
 }*/
 
 impl ServerInstance {
-    fn new(path: &String) -> Self {
-        let (teams, init, map) = save::read_scenario(path);
-        let server = Server::new(init, map);
+    fn new(path: &String) -> Result<Self, save::ScenarioError> {
+        let (teams, init, map, rules, bounds, info) =
+            save::read_scenario(path)?;
+        let server = Server::new(init, map, rules);
         //let read_timeout = Some(time::Duration::from_millis(100));
-        ServerInstance {
+        Ok(ServerInstance {
             teams,
             server,
+            bounds,
+            info,
 
             players: HashMap::new(),
             player_names: HashMap::new(),
-        }
+        })
     }
 
-    fn add_player(self: &mut Self, team: TID, player: net::TcpStream) {
+    // gives the player the team they asked for if it is still open,
+    // otherwise the lowest numbered open team
+    fn add_player(
+        self: &mut Self,
+        open: &mut BTreeSet<TID>,
+        player: net::TcpStream,
+    ) {
         let name = ::bincode::deserialize_from(&player)
             .expect("Failed to read player name");
+        let preference: Option<TID> = ::bincode::deserialize_from(&player)
+            .expect("Failed to read team preference");
+        let team = match preference {
+            Some(team) if open.contains(&team) => team,
+            _ => *open.iter().next().expect("No open teams"),
+        };
+        open.remove(&team);
         self.player_names.insert(team, name);
 
+        ::bincode::serialize_into(&player, &team)
+            .expect("Failed to send team");
         ::bincode::serialize_into(&player, &self.server.map)
             .expect("Failed to send map");
+        ::bincode::serialize_into(&player, &self.bounds)
+            .expect("Failed to send arena bounds");
+        ::bincode::serialize_into(&player, &self.info)
+            .expect("Failed to send scenario info");
+        ::bincode::serialize_into(&player, &self.server.rules)
+            .expect("Failed to send rules");
         ::bincode::serialize_into(&player, &self.server.current)
             .expect("Failed to send unit state");
         self.players.insert(team, player);
@@ -88,8 +126,9 @@ impl ServerInstance {
 
     fn send_roster(self: &Self) -> Result<(), Box<::bincode::ErrorKind>> {
         let mut intro = "The following players have joined: \n".to_string();
-        for (team, name) in &self.player_names {
-            intro.push_str(&*format!(" {}: {}\n", team+1, name));
+        for (&team, name) in &self.player_names {
+            let team = self.info.team(team).name;
+            intro.push_str(&*format!(" {}: {}\n", team, name));
         }
         for (_, player) in &self.players {
             ::bincode::serialize_into(player, &intro)?;
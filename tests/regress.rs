@@ -0,0 +1,32 @@
+extern crate tactics;
+
+use std::fs;
+use std::path::Path;
+
+use tactics::regress::{self, Outcome};
+
+// every case in the regress directory has to match its golden file
+#[test]
+fn regress_cases() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("regress");
+    let mut cases: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "case"))
+        .collect();
+    cases.sort();
+    assert!(cases.len() > 0, "No cases in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for case in &cases {
+        match regress::run_case(case, false) {
+            Ok(Outcome::Pass) => (),
+            Ok(Outcome::Fail(diff)) => {
+                failures.push(format!("{}:\n{}", case.display(), diff));
+            },
+            Ok(Outcome::Blessed) => unreachable!(),
+            Err(msg) => failures.push(format!("{}: {}", case.display(), msg)),
+        }
+    }
+    assert!(failures.len() == 0, "\n{}", failures.join("\n"));
+}